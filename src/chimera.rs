@@ -0,0 +1,274 @@
+use std::error::Error;
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use log::info;
+
+use crate::cluster::load_reference;
+use crate::derep::dereplicate;
+use crate::{ColumnWeights, SeqEncoding};
+
+#[derive(Debug, Clone)]
+pub struct ChimeraOptions {
+    /// Search against the windows of this database instead of more abundant
+    /// input sequences.
+    pub reference_db: Option<PathBuf>,
+    /// Without a reference, parents must be at least this many times as
+    /// abundant as the sequence.
+    pub min_parent_skew: f64,
+    /// Maximum divergence of a chimera from its two parents.
+    pub max_divergence: u32,
+    /// Minimum amount by which two parents must explain a chimera better than
+    /// the closest single parent.
+    pub min_improvement: u32,
+    pub column_weights: ColumnWeights,
+}
+
+impl Default for ChimeraOptions {
+    fn default() -> Self {
+        ChimeraOptions {
+            reference_db: None,
+            min_parent_skew: 2.0,
+            max_divergence: 1,
+            min_improvement: 2,
+            column_weights: ColumnWeights::default(),
+        }
+    }
+}
+
+/// The closest parent to a sequence, and the closest pair of parents where the
+/// left parent gives the columns before the breakpoint and the right parent
+/// the rest.
+struct ParentSearch {
+    parent: usize,
+    distance: usize,
+    /// (left parent, right parent, breakpoint, distance), or None for
+    /// sequences too short to have a breakpoint.
+    two_parents: Option<(usize, usize, usize, usize)>,
+}
+
+impl ParentSearch {
+    fn is_chimera(&self, options: &ChimeraOptions) -> bool {
+        match self.two_parents {
+            Some((_, _, _, distance)) => {
+                distance <= options.max_divergence as usize
+                    && self.distance >= distance + options.min_improvement as usize
+            }
+            None => false,
+        }
+    }
+}
+
+/// First index and value of the minimum of an iterator.
+fn first_min(values: impl Iterator<Item = usize>) -> (usize, usize) {
+    values
+        .enumerate()
+        .min_by_key(|(_, value)| *value)
+        .expect("Cannot take the minimum of nothing")
+}
+
+/// Find the closest single parent and pair of parents of a sequence, by the
+/// weighted number of differing columns. Returns None if there are no parents.
+fn search_parents(
+    seq: &SeqEncoding,
+    parents: &[&SeqEncoding],
+    weights: &[u32],
+) -> Option<ParentSearch> {
+    if parents.is_empty() {
+        return None;
+    }
+    let len = weights.len();
+    // Weighted differences to each parent in the columns before each position
+    let prefixes = parents
+        .iter()
+        .map(|parent| {
+            let mut prefix = Vec::with_capacity(len + 1);
+            prefix.push(0);
+            for (column, weight) in weights.iter().enumerate() {
+                let shift = 5 * (column % 12);
+                let differs =
+                    (seq.0[column / 12] >> shift) & 31 != (parent.0[column / 12] >> shift) & 31;
+                prefix.push(prefix[column] + if differs { *weight as usize } else { 0 });
+            }
+            prefix
+        })
+        .collect::<Vec<_>>();
+
+    let (parent, distance) = first_min(prefixes.iter().map(|prefix| prefix[len]));
+    let mut two_parents: Option<(usize, usize, usize, usize)> = None;
+    for breakpoint in 1..len {
+        let (left, left_distance) = first_min(prefixes.iter().map(|prefix| prefix[breakpoint]));
+        let (right, right_distance) = first_min(
+            prefixes
+                .iter()
+                .map(|prefix| prefix[len] - prefix[breakpoint]),
+        );
+        let two_parent_distance = left_distance + right_distance;
+        let better = match two_parents {
+            Some((_, _, _, best)) => two_parent_distance < best,
+            None => true,
+        };
+        if better {
+            two_parents = Some((left, right, breakpoint, two_parent_distance));
+        }
+    }
+    Some(ParentSearch {
+        parent,
+        distance,
+        two_parents,
+    })
+}
+
+/// Flag sequences better explained as a chimera of two parents than as
+/// derived from one. Since sequences are aligned, the left part of a chimera
+/// is compared to the same columns of one parent and the right part to
+/// another. Without a reference database, the parents of each sequence are
+/// the sufficiently more abundant input sequences not themselves flagged.
+///
+/// For each input sequence in input order, a line is written giving its
+/// identifier, whether it is a chimera, its closest parent and divergence from
+/// it, and the left parent, right parent, breakpoint and divergence of the best
+/// pair of parents. The breakpoint is the number of columns taken from the left
+/// parent.
+pub fn chimera(
+    input: &Path,
+    options: &ChimeraOptions,
+    output: &mut dyn std::io::Write,
+) -> Result<(), Box<dyn Error>> {
+    let start = Instant::now();
+    let reference = match &options.reference_db {
+        Some(db_path) => Some(load_reference(db_path)?),
+        None => None,
+    };
+    let mut dereplicated = dereplicate(input)?;
+    dereplicated.sort_by_abundance();
+    let uniques = &dereplicated.uniques;
+
+    let weights = match uniques.first() {
+        Some(unique) => {
+            if let Some(len) = reference
+                .as_ref()
+                .and_then(|r| r.len)
+                .map(NonZeroUsize::get)
+            {
+                if len != unique.encoding.len {
+                    return Err(format!(
+                        "Reference windows are length {}, but input sequences are length {}",
+                        len, unique.encoding.len
+                    )
+                    .into());
+                }
+            }
+            options
+                .column_weights
+                .check_masked_columns(unique.encoding.len)?;
+            options.column_weights.column_weights(unique.encoding.len)?
+        }
+        None => vec![],
+    };
+
+    info!("Searching for chimeras ..");
+    let mut searches: Vec<Option<ParentSearch>> = Vec::with_capacity(uniques.len());
+    let mut non_chimeras: Vec<usize> = vec![];
+    for (unique_index, unique) in uniques.iter().enumerate() {
+        // Indices of the parents in the uniques or reference windows
+        let (indices, parents): (Vec<usize>, Vec<&SeqEncoding>) = match &reference {
+            Some(reference) => reference.windows.iter().enumerate().unzip(),
+            None => non_chimeras
+                .iter()
+                .filter(|i| {
+                    uniques[**i].abundance as f64
+                        >= options.min_parent_skew * unique.abundance as f64
+                })
+                .map(|i| (*i, &uniques[*i].encoding.encoding))
+                .unzip(),
+        };
+        let search =
+            search_parents(&unique.encoding.encoding, &parents, &weights).map(|s| ParentSearch {
+                parent: indices[s.parent],
+                distance: s.distance,
+                two_parents: s.two_parents.map(|(left, right, breakpoint, distance)| {
+                    (indices[left], indices[right], breakpoint, distance)
+                }),
+            });
+        if !search.as_ref().is_some_and(|s| s.is_chimera(options)) {
+            non_chimeras.push(unique_index);
+        }
+        searches.push(search);
+    }
+
+    let parent_id = |index: usize| -> String {
+        match reference {
+            Some(_) => format!("reference_{}", index),
+            None => uniques[index].id.clone(),
+        }
+    };
+    let mut num_chimeras = 0;
+    for (id, unique_index) in &dereplicated.records {
+        match &searches[*unique_index] {
+            Some(search) => {
+                let is_chimera = search.is_chimera(options);
+                if is_chimera {
+                    num_chimeras += 1;
+                }
+                write!(
+                    output,
+                    "{}\t{}\t{}\t{}",
+                    id,
+                    is_chimera,
+                    parent_id(search.parent),
+                    search.distance
+                )?;
+                match search.two_parents {
+                    Some((left, right, breakpoint, distance)) => writeln!(
+                        output,
+                        "\t{}\t{}\t{}\t{}",
+                        parent_id(left),
+                        parent_id(right),
+                        breakpoint,
+                        distance
+                    )?,
+                    None => writeln!(output, "\tNA\tNA\tNA\tNA")?,
+                }
+            }
+            None => writeln!(output, "{}\tfalse\tNA\tNA\tNA\tNA\tNA\tNA", id)?,
+        }
+    }
+    output.flush()?;
+
+    info!(
+        "Chimera search complete, took {} seconds. Found {} chimeras in {} sequences.",
+        start.elapsed().as_secs(),
+        num_chimeras,
+        dereplicated.records.len()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_chimera() {
+        let mut stream = Cursor::new(Vec::new());
+        chimera(
+            Path::new("tests/data/chimera.fna"),
+            &ChimeraOptions::default(),
+            &mut stream,
+        )
+        .unwrap();
+        // p1 and p2 are not abundant enough to be each other's parents. c1 is
+        // the first 4 columns of p1 and the last 4 of p2, so is not a parent of
+        // c2, which differs from p1 and p2 too much to be a chimera of them.
+        assert_eq!(
+            "p1\tfalse\tNA\tNA\tNA\tNA\tNA\tNA\n\
+             p2\tfalse\tNA\tNA\tNA\tNA\tNA\tNA\n\
+             c1\ttrue\tp1\t4\tp1\tp2\t4\t0\n\
+             c2\tfalse\tp1\t4\tp1\tp2\t4\t2\n",
+            std::str::from_utf8(stream.get_ref()).unwrap()
+        );
+    }
+}
@@ -1,94 +1,1272 @@
-use needletail::parse_fastx_file;
-
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashMap};
 use std::error::Error;
-use std::path::Path;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
 use std::time::Instant;
 
 use log::debug;
 use log::info;
+use log::warn;
+use serde::Serialize;
+
+use crate::derep::{dereplicate, Dereplicated, UniqueSequence};
+use crate::{
+    load_targets, write_db, ColumnWeights, SeqEncodingLength, WeightMasks, WindowSet,
+    CURRENT_DB_VERSION,
+};
+
+#[derive(Debug, Clone, Default)]
+pub struct ClusterOptions {
+    pub max_divergence: u32,
+    pub column_weights: ColumnWeights,
+    /// Process unique sequences in order of decreasing abundance rather than
+    /// input order, so that abundant sequences become centroids.
+    pub sort_by_abundance: bool,
+    /// Write the centroid and total abundance of each cluster here.
+    pub cluster_sizes_output: Option<PathBuf>,
+    /// Write the cluster of every input sequence here.
+    pub membership_output: Option<PathBuf>,
+    /// Write the centroids in FASTA format here, annotated with cluster sizes.
+    pub centroids_output: Option<PathBuf>,
+    /// Write the centroids here as a database which can be queried.
+    pub centroids_db: Option<PathBuf>,
+    /// Database whose windows are the first centroids, keeping their numbers.
+    pub reference_db: Option<PathBuf>,
+    /// Do not create new centroids, leaving sequences which are not within the
+    /// max divergence of a reference centroid unassigned.
+    pub closed_reference: bool,
+    /// Write the input sequences which are not assigned to a cluster here.
+    pub unassigned_output: Option<PathBuf>,
+    /// After clustering, move each sequence to its closest centroid, which
+    /// may have been created after the sequence was assigned.
+    pub reassign: bool,
+    /// Increasing divergences, each larger than max_divergence, at which to
+    /// cluster the centroids of the previous level.
+    pub coarser_divergences: Vec<u32>,
+    /// Write the cluster of every input sequence at each level here.
+    pub hierarchy_output: Option<PathBuf>,
+    /// Make clusters the connected components of sequences within the max
+    /// divergence of each other, instead of clustering greedily.
+    pub single_linkage: bool,
+    /// Number of threads to compare sequences to centroids with.
+    pub threads: usize,
+    /// Write the majority consensus of each cluster's members in place of its
+    /// centroid in the centroid outputs.
+    pub consensus: bool,
+    /// Write the support for each column of each consensus here.
+    pub consensus_support_output: Option<PathBuf>,
+    /// Write summary statistics of the clustering here in JSON format.
+    pub stats_output: Option<PathBuf>,
+    /// Remove clusters with a smaller total abundance than this, other than
+    /// those of reference centroids.
+    pub min_cluster_size: usize,
+    /// Move members of removed clusters to the closest remaining centroid
+    /// within this divergence, rather than leaving them unassigned.
+    pub small_cluster_max_divergence: Option<u32>,
+    /// Partition the sequences into this many clusters around medoids,
+    /// ignoring max_divergence, instead of clustering greedily.
+    pub k_medoids: Option<usize>,
+    /// Seed of the random choice of initial medoids.
+    pub seed: u64,
+}
+
+/// Number of sequences given to each thread at a time when clustering
+/// greedily. Larger batches mean fewer synchronisations, but more comparisons
+/// to centroids created during the batch, which are not parallelised.
+const BATCH_SIZE_PER_THREAD: usize = 256;
+
+/// Maximum number of rounds of assigning sequences to medoids and updating
+/// the medoids in k-medoids clustering.
+const MAX_K_MEDOIDS_ITERATIONS: usize = 100;
+
+/// Greedy assignment of unique sequences to centroids.
+struct Clustering {
+    centroids: WindowSet,
+    /// Index of the unique sequence which is the centroid of each cluster, or
+    /// None for centroids from the reference database.
+    centroid_uniques: Vec<Option<usize>>,
+    /// Cluster number and distance to its centroid of each unique sequence,
+    /// or None if it is unassigned.
+    assignments: Vec<Option<(usize, usize)>>,
+    /// Whether the cluster of each unique sequence changed when reassigned,
+    /// or None if there was no reassignment.
+    reassigned: Option<Vec<bool>>,
+    weight_masks: Option<WeightMasks>,
+}
+
+impl Clustering {
+    /// Total abundance of the members of each cluster.
+    fn cluster_sizes(&self, uniques: &[UniqueSequence]) -> Vec<usize> {
+        let mut sizes = vec![0; self.centroids.windows.len()];
+        for (unique, assignment) in uniques.iter().zip(self.assignments.iter()) {
+            if let Some((cluster_number, _)) = assignment {
+                sizes[*cluster_number] += unique.abundance;
+            }
+        }
+        sizes
+    }
+
+    /// Move each sequence which is not a centroid to its closest centroid, if
+    /// closer than its current one, now that all centroids are known. Ties go
+    /// to the lowest numbered centroid.
+    fn reassign(&mut self, uniques: &[UniqueSequence], threads: usize) {
+        let mut is_centroid = vec![false; uniques.len()];
+        for unique_index in self.centroid_uniques.iter().flatten() {
+            is_centroid[*unique_index] = true;
+        }
+        let closest = closest_windows(
+            &self.centroids,
+            uniques,
+            self.weight_masks.as_ref(),
+            threads,
+        );
+        let mut reassigned = vec![false; uniques.len()];
+        for (unique_index, closest) in closest.into_iter().enumerate() {
+            let current_distance = match self.assignments[unique_index] {
+                Some((_, distance)) if !is_centroid[unique_index] => distance,
+                _ => continue,
+            };
+            let (best, best_distance) = closest.unwrap();
+            if best_distance < current_distance {
+                debug!("Reassigned to centroid: {}", best);
+                self.assignments[unique_index] = Some((best, best_distance));
+                reassigned[unique_index] = true;
+            }
+        }
+        self.reassigned = Some(reassigned);
+    }
+
+    /// Remove clusters, other than those of reference centroids, with a total
+    /// abundance less than min_size, renumbering the remaining clusters in
+    /// order. Members of removed clusters are moved to the closest remaining
+    /// centroid within max_divergence if given, or otherwise left unassigned.
+    /// Moved members are recorded as reassigned if there was a reassignment.
+    fn remove_small_clusters(
+        &mut self,
+        uniques: &[UniqueSequence],
+        min_size: usize,
+        max_divergence: Option<u32>,
+    ) {
+        let sizes = self.cluster_sizes(uniques);
+        let mut new_numbers = vec![None; sizes.len()];
+        let mut windows = vec![];
+        let mut centroid_uniques = vec![];
+        for (cluster_number, size) in sizes.iter().enumerate() {
+            if self.centroid_uniques[cluster_number].is_none() || *size >= min_size {
+                new_numbers[cluster_number] = Some(windows.len());
+                windows.push(self.centroids.windows[cluster_number].clone());
+                centroid_uniques.push(self.centroid_uniques[cluster_number]);
+            }
+        }
+        info!(
+            "Removing {} clusters smaller than {}",
+            sizes.len() - windows.len(),
+            min_size
+        );
+        self.centroids.windows = windows;
+        if self.centroids.windows.is_empty() {
+            self.centroids.len = None;
+        }
+        self.centroid_uniques = centroid_uniques;
+
+        let mut distances = vec![0; self.centroids.windows.len()];
+        for (unique_index, (unique, assignment)) in
+            uniques.iter().zip(self.assignments.iter_mut()).enumerate()
+        {
+            let (cluster_number, distance) = match assignment {
+                Some(assigned) => *assigned,
+                None => continue,
+            };
+            *assignment = match (new_numbers[cluster_number], max_divergence) {
+                (Some(new_number), _) => Some((new_number, distance)),
+                (None, Some(max_divergence)) => {
+                    self.centroids.get_distances(
+                        &unique.encoding,
+                        self.weight_masks.as_ref(),
+                        &mut distances,
+                    );
+                    let closest = distances
+                        .iter()
+                        .copied()
+                        .enumerate()
+                        .min_by_key(|(_, distance)| *distance)
+                        .filter(|(_, distance)| *distance <= max_divergence as usize);
+                    if let (Some(reassigned), Some(_)) = (self.reassigned.as_mut(), closest) {
+                        reassigned[unique_index] = true;
+                    }
+                    closest
+                }
+                (None, None) => None,
+            };
+        }
+    }
+
+    /// Identifier of the centroid of a cluster. Reference centroids are named
+    /// by their number in the reference database.
+    fn centroid_id(&self, cluster_number: usize, uniques: &[UniqueSequence]) -> String {
+        match self.centroid_uniques[cluster_number] {
+            Some(unique_index) => uniques[unique_index].id.clone(),
+            None => format!("reference_{}", cluster_number),
+        }
+    }
+
+    /// Sequence of the centroid of a cluster, as it was in the input if it
+    /// was an input sequence.
+    fn centroid_sequence(&self, cluster_number: usize, uniques: &[UniqueSequence]) -> String {
+        match self.centroid_uniques[cluster_number] {
+            Some(unique_index) => String::from_utf8_lossy(&uniques[unique_index].seq).into_owned(),
+            None => self.centroids.get_as_string(cluster_number),
+        }
+    }
+
+    /// The centroids as sequences to be clustered, with the size of their
+    /// cluster as their abundance.
+    fn centroids_as_uniques(&self, uniques: &[UniqueSequence]) -> Vec<UniqueSequence> {
+        self.cluster_sizes(uniques)
+            .into_iter()
+            .enumerate()
+            .map(|(cluster_number, abundance)| {
+                let id = self.centroid_id(cluster_number, uniques);
+                let seq = self.centroid_sequence(cluster_number, uniques).into_bytes();
+                UniqueSequence {
+                    encoding: SeqEncodingLength::from_bytes(id.as_bytes(), &seq),
+                    id,
+                    seq,
+                    abundance,
+                }
+            })
+            .collect()
+    }
+}
+
+/// Summary statistics of a clustering.
+#[derive(Serialize, Debug)]
+struct ClusterStats {
+    num_sequences: usize,
+    num_unique_sequences: usize,
+    /// Number of input sequences identical to an earlier one
+    num_duplicates: usize,
+    num_unassigned: usize,
+    num_clusters: usize,
+    /// Number of clusters with a total abundance of 1
+    num_singletons: usize,
+    /// Number of clusters with each total abundance, smallest first
+    cluster_size_distribution: Vec<ClusterSizeCount>,
+    clusters: Vec<ClusterSummary>,
+    runtime_seconds: f64,
+}
+
+#[derive(Serialize, Debug)]
+struct ClusterSizeCount {
+    size: usize,
+    num_clusters: usize,
+}
+
+#[derive(Serialize, Debug)]
+struct ClusterSummary {
+    cluster: usize,
+    centroid: String,
+    size: usize,
+    /// Distance of members from the centroid, weighted by abundance. None for
+    /// clusters without members.
+    mean_distance: Option<f64>,
+    max_distance: Option<usize>,
+}
+
+impl ClusterStats {
+    fn new(
+        dereplicated: &Dereplicated,
+        clustering: &Clustering,
+        num_unassigned: usize,
+        runtime_seconds: f64,
+    ) -> Self {
+        let uniques = &dereplicated.uniques;
+        let sizes = clustering.cluster_sizes(uniques);
+        let mut total_distances = vec![0; sizes.len()];
+        let mut max_distances: Vec<Option<usize>> = vec![None; sizes.len()];
+        for (unique, assignment) in uniques.iter().zip(clustering.assignments.iter()) {
+            if let Some((cluster_number, distance)) = assignment {
+                total_distances[*cluster_number] += distance * unique.abundance;
+                let max_distance = &mut max_distances[*cluster_number];
+                *max_distance = Some(max_distance.map_or(*distance, |d| d.max(*distance)));
+            }
+        }
+
+        let mut size_counts = BTreeMap::new();
+        for size in &sizes {
+            *size_counts.entry(*size).or_insert(0) += 1;
+        }
+
+        ClusterStats {
+            num_sequences: dereplicated.records.len(),
+            num_unique_sequences: uniques.len(),
+            num_duplicates: dereplicated.records.len() - uniques.len(),
+            num_unassigned,
+            num_clusters: sizes.len(),
+            num_singletons: sizes.iter().filter(|size| **size == 1).count(),
+            cluster_size_distribution: size_counts
+                .into_iter()
+                .map(|(size, num_clusters)| ClusterSizeCount { size, num_clusters })
+                .collect(),
+            clusters: sizes
+                .iter()
+                .enumerate()
+                .map(|(cluster_number, size)| ClusterSummary {
+                    cluster: cluster_number,
+                    centroid: clustering.centroid_id(cluster_number, uniques),
+                    size: *size,
+                    mean_distance: (*size > 0)
+                        .then(|| total_distances[cluster_number] as f64 / *size as f64),
+                    max_distance: max_distances[cluster_number],
+                })
+                .collect(),
+            runtime_seconds,
+        }
+    }
+}
+
+/// Representative sequence of a cluster built from its members.
+struct Consensus {
+    sequence: Vec<u8>,
+    /// Fraction of the cluster's abundance with the consensus base in each
+    /// column, or None if the cluster has no members.
+    support: Option<Vec<f64>>,
+}
+
+/// The per-column majority consensus of the members of each cluster, weighted
+/// by abundance. Bases vote by their encoding, as when calculating distances,
+/// so T and U vote together, as do gaps, N and other ambiguity codes. The
+/// winning symbol is written as it appears in the first member with it.
+/// Columns where symbols are tied are given as N. Clusters without members
+/// keep their centroid.
+fn consensus_sequences(clustering: &Clustering, uniques: &[UniqueSequence]) -> Vec<Consensus> {
+    let len = clustering.centroids.len.map(NonZeroUsize::get).unwrap_or(0);
+    // Encoded symbol, its first base and its abundance in each column of each
+    // cluster
+    let mut counts: Vec<Vec<Vec<(u64, u8, usize)>>> =
+        vec![vec![vec![]; len]; clustering.centroids.windows.len()];
+    for (unique, assignment) in uniques.iter().zip(clustering.assignments.iter()) {
+        if let Some((cluster_number, _)) = assignment {
+            let encoding = &unique.encoding.encoding.0;
+            for (column, (column_counts, base)) in counts[*cluster_number]
+                .iter_mut()
+                .zip(unique.seq.iter())
+                .enumerate()
+            {
+                let symbol = (encoding[column / 12] >> (5 * (column % 12))) & 31;
+                match column_counts.iter_mut().find(|(s, _, _)| *s == symbol) {
+                    Some((_, _, count)) => *count += unique.abundance,
+                    None => {
+                        column_counts.push((symbol, base.to_ascii_uppercase(), unique.abundance))
+                    }
+                }
+            }
+        }
+    }
+
+    counts
+        .into_iter()
+        .enumerate()
+        .map(|(cluster_number, cluster_counts)| {
+            let total = cluster_counts.first().map_or(0, |column_counts| {
+                column_counts.iter().map(|(_, _, n)| n).sum()
+            });
+            if total == 0 {
+                return Consensus {
+                    sequence: clustering
+                        .centroid_sequence(cluster_number, uniques)
+                        .into_bytes(),
+                    support: None,
+                };
+            }
+            let (sequence, support) = cluster_counts
+                .iter()
+                .map(|column_counts| {
+                    let max = column_counts.iter().map(|(_, _, n)| *n).max().unwrap();
+                    let mut most_common = column_counts.iter().filter(|(_, _, n)| *n == max);
+                    let base = match (most_common.next(), most_common.next()) {
+                        (Some((_, base, _)), None) => *base,
+                        _ => b'N',
+                    };
+                    (base, max as f64 / total as f64)
+                })
+                .unzip();
+            Consensus {
+                sequence,
+                support: Some(support),
+            }
+        })
+        .collect()
+}
+
+/// Disjoint sets of unique sequences, for single linkage clustering.
+struct UnionFind {
+    parents: Vec<usize>,
+}
+
+impl UnionFind {
+    fn new(len: usize) -> Self {
+        UnionFind {
+            parents: (0..len).collect(),
+        }
+    }
+
+    fn find(&mut self, mut i: usize) -> usize {
+        while self.parents[i] != i {
+            self.parents[i] = self.parents[self.parents[i]];
+            i = self.parents[i];
+        }
+        i
+    }
+
+    /// Join two sets, keeping the smallest index as the root.
+    fn union(&mut self, a: usize, b: usize) {
+        let (a, b) = (self.find(a), self.find(b));
+        if a < b {
+            self.parents[b] = a;
+        } else {
+            self.parents[a] = b;
+        }
+    }
+}
+
+/// Split the columns with non-zero weight into runs of near-equal size, as
+/// bitmasks over the encoding. Since each differing column adds at least 1 to
+/// the distance, two sequences within num_segments - 1 of each other must be
+/// identical in at least one segment.
+fn segment_masks(weights: &[u32], num_segments: usize) -> Vec<Vec<u64>> {
+    let columns = weights
+        .iter()
+        .enumerate()
+        .filter(|(_, weight)| **weight > 0)
+        .map(|(column, _)| column)
+        .collect::<Vec<_>>();
+    let num_chunks = weights.len().div_ceil(12);
+    let mut masks = (0..num_segments)
+        .map(|segment| {
+            let mut mask = vec![0; num_chunks];
+            let start = segment * columns.len() / num_segments;
+            let end = (segment + 1) * columns.len() / num_segments;
+            for column in &columns[start..end] {
+                mask[column / 12] |= 0b11111 << (5 * (column % 12));
+            }
+            mask
+        })
+        .filter(|mask| mask.iter().any(|m| *m != 0))
+        .collect::<Vec<_>>();
+    // With more segments than columns, all pairs must be compared, which an
+    // empty mask does.
+    if masks.len() < num_segments {
+        masks.push(vec![0; num_chunks]);
+    }
+    masks
+}
+
+/// Cluster unique sequences into the connected components of the graph
+/// joining each pair within the max divergence. Only pairs identical in at
+/// least one segment are compared. The centroid of each cluster is its first
+/// member, and clusters are numbered in order of their centroids.
+fn single_linkage_cluster(
+    uniques: &[UniqueSequence],
+    options: &ClusterOptions,
+) -> Result<Clustering, Box<dyn Error>> {
+    let max_divergence_usize = options.max_divergence as usize;
+    let mut centroids = WindowSet::new(CURRENT_DB_VERSION);
+    let mut centroid_uniques = vec![];
+    let mut assignments = Vec::with_capacity(uniques.len());
+    let len = match uniques.first() {
+        Some(unique) => unique.encoding.len,
+        None => {
+            return Ok(Clustering {
+                centroids,
+                centroid_uniques,
+                assignments,
+                reassigned: None,
+                weight_masks: None,
+            })
+        }
+    };
+    let weight_masks = options.column_weights.to_masks(len)?;
+    let weights = options.column_weights.column_weights(len)?;
+
+    let mut sets = UnionFind::new(uniques.len());
+    for mask in segment_masks(&weights, max_divergence_usize + 1) {
+        let mut buckets = HashMap::<Vec<u64>, Vec<usize>>::new();
+        for (unique_index, unique) in uniques.iter().enumerate() {
+            let key = unique
+                .encoding
+                .encoding
+                .0
+                .iter()
+                .zip(mask.iter())
+                .map(|(e, m)| e & m)
+                .collect();
+            buckets.entry(key).or_default().push(unique_index);
+        }
+        for members in buckets.values() {
+            for (i, a) in members.iter().enumerate() {
+                for b in &members[i + 1..] {
+                    if sets.find(*a) != sets.find(*b)
+                        && uniques[*a]
+                            .encoding
+                            .encoding
+                            .distance(&uniques[*b].encoding.encoding, weight_masks.as_ref())
+                            <= max_divergence_usize
+                    {
+                        sets.union(*a, *b);
+                    }
+                }
+            }
+        }
+    }
+
+    // The root of each set is its first member, so is seen first.
+    let mut cluster_numbers = vec![0; uniques.len()];
+    for (unique_index, unique) in uniques.iter().enumerate() {
+        let root = sets.find(unique_index);
+        if root == unique_index {
+            cluster_numbers[unique_index] = centroid_uniques.len();
+            centroids.push_encoding(unique.encoding.clone());
+            centroid_uniques.push(Some(unique_index));
+        }
+        let distance = uniques[root]
+            .encoding
+            .encoding
+            .distance(&unique.encoding.encoding, weight_masks.as_ref());
+        assignments.push(Some((cluster_numbers[root], distance)));
+    }
+    Ok(Clustering {
+        centroids,
+        centroid_uniques,
+        assignments,
+        reassigned: None,
+        weight_masks,
+    })
+}
+
+/// Cluster the centroids of each level at the next of the coarser
+/// divergences, so that each cluster is within a single cluster of every
+/// coarser level. Returns, for each coarser level, the cluster of each cluster
+/// of the finest level.
+fn cluster_hierarchy(
+    clustering: &Clustering,
+    uniques: &[UniqueSequence],
+    options: &ClusterOptions,
+) -> Result<Vec<Vec<usize>>, Box<dyn Error>> {
+    // Connected components at a larger divergence are unions of those at a
+    // smaller one, so each level is clustered from the sequences themselves.
+    if options.single_linkage {
+        return options
+            .coarser_divergences
+            .iter()
+            .map(|max_divergence| {
+                info!("Clustering at divergence {} ..", max_divergence);
+                let level_clustering = single_linkage_cluster(
+                    uniques,
+                    &ClusterOptions {
+                        max_divergence: *max_divergence,
+                        column_weights: options.column_weights.clone(),
+                        threads: options.threads,
+                        ..Default::default()
+                    },
+                )?;
+                Ok(clustering
+                    .centroid_uniques
+                    .iter()
+                    .map(|unique_index| {
+                        level_clustering.assignments[unique_index.unwrap()]
+                            .unwrap()
+                            .0
+                    })
+                    .collect())
+            })
+            .collect();
+    }
+
+    // Each level is treated as a dereplication of the finest clusters.
+    let mut level = Dereplicated {
+        uniques: clustering.centroids_as_uniques(uniques),
+        records: (0..clustering.centroids.windows.len())
+            .map(|cluster_number| {
+                (
+                    clustering.centroid_id(cluster_number, uniques),
+                    cluster_number,
+                )
+            })
+            .collect(),
+    };
+    let mut hierarchy = Vec::with_capacity(options.coarser_divergences.len());
+    for max_divergence in &options.coarser_divergences {
+        info!("Clustering centroids at divergence {} ..", max_divergence);
+        if options.sort_by_abundance {
+            level.sort_by_abundance();
+        }
+        let level_options = ClusterOptions {
+            max_divergence: *max_divergence,
+            column_weights: options.column_weights.clone(),
+            threads: options.threads,
+            ..Default::default()
+        };
+        let mut level_clustering = greedy_cluster(&level.uniques, None, &level_options)?;
+        if options.reassign {
+            level_clustering.reassign(&level.uniques, options.threads);
+        }
+
+        let records = level
+            .records
+            .into_iter()
+            .map(|(id, unique_index)| (id, level_clustering.assignments[unique_index].unwrap().0))
+            .collect::<Vec<_>>();
+        hierarchy.push(
+            records
+                .iter()
+                .map(|(_, cluster_number)| *cluster_number)
+                .collect(),
+        );
+        level = Dereplicated {
+            uniques: level_clustering.centroids_as_uniques(&level.uniques),
+            records,
+        };
+    }
+    Ok(hierarchy)
+}
+
+/// Write a table with a header, giving the cluster of every input sequence in
+/// input order at each level, or NA if it is unassigned.
+fn write_hierarchy(
+    dereplicated: &Dereplicated,
+    clustering: &Clustering,
+    hierarchy: &[Vec<usize>],
+    options: &ClusterOptions,
+    writer: &mut dyn Write,
+) -> Result<(), Box<dyn Error>> {
+    write!(writer, "read\tmax_divergence_{}", options.max_divergence)?;
+    for max_divergence in &options.coarser_divergences {
+        write!(writer, "\tmax_divergence_{}", max_divergence)?;
+    }
+    writeln!(writer)?;
+    for (id, unique_index) in &dereplicated.records {
+        write!(writer, "{}", id)?;
+        match clustering.assignments[*unique_index] {
+            Some((cluster_number, _)) => {
+                write!(writer, "\t{}", cluster_number)?;
+                for level in hierarchy {
+                    write!(writer, "\t{}", level[cluster_number])?;
+                }
+            }
+            None => {
+                for _ in 0..=hierarchy.len() {
+                    write!(writer, "\tNA")?;
+                }
+            }
+        }
+        writeln!(writer)?;
+    }
+    Ok(())
+}
+
+/// The first closest window to each sequence and its distance, or None if
+/// there are no windows. Sequences are split between threads.
+fn closest_windows(
+    windows: &WindowSet,
+    uniques: &[UniqueSequence],
+    weight_masks: Option<&WeightMasks>,
+    threads: usize,
+) -> Vec<Option<(usize, usize)>> {
+    let closest_in_chunk = |chunk: &[UniqueSequence]| {
+        let mut distances = vec![0; windows.windows.len()];
+        chunk
+            .iter()
+            .map(|unique| {
+                windows.get_distances(&unique.encoding, weight_masks, &mut distances);
+                distances
+                    .iter()
+                    .copied()
+                    .enumerate()
+                    .min_by_key(|(_, distance)| *distance)
+            })
+            .collect::<Vec<_>>()
+    };
+    if threads <= 1 || uniques.len() < 2 {
+        return closest_in_chunk(uniques);
+    }
+    let chunk_size = uniques.len().div_ceil(threads);
+    std::thread::scope(|scope| {
+        let handles = uniques
+            .chunks(chunk_size)
+            .map(|chunk| scope.spawn(move || closest_in_chunk(chunk)))
+            .collect::<Vec<_>>();
+        handles
+            .into_iter()
+            .flat_map(|handle| handle.join().unwrap())
+            .collect()
+    })
+}
+
+/// SplitMix64 pseudorandom number generator, so that the choice of initial
+/// medoids is reproducible from a seed.
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// Choose an index with probability proportional to its weight, or None
+    /// if all weights are zero.
+    fn choose_weighted(&mut self, weights: &[u64]) -> Option<usize> {
+        let total: u128 = weights.iter().map(|w| *w as u128).sum();
+        if total == 0 {
+            return None;
+        }
+        let mut target = self.next_u64() as u128 % total;
+        weights.iter().position(|w| {
+            if target < *w as u128 {
+                true
+            } else {
+                target -= *w as u128;
+                false
+            }
+        })
+    }
+}
+
+/// The member of a cluster with the smallest abundance-weighted total
+/// distance to the other members, preferring the current medoid on ties.
+fn cluster_medoid(
+    members: &[usize],
+    current: usize,
+    uniques: &[UniqueSequence],
+    weight_masks: Option<&WeightMasks>,
+    threads: usize,
+) -> usize {
+    let costs_in_chunk = |chunk: &[usize]| {
+        chunk
+            .iter()
+            .map(|candidate| {
+                members
+                    .iter()
+                    .map(|member| {
+                        uniques[*member].abundance
+                            * uniques[*candidate]
+                                .encoding
+                                .encoding
+                                .distance(&uniques[*member].encoding.encoding, weight_masks)
+                    })
+                    .sum::<usize>()
+            })
+            .collect::<Vec<_>>()
+    };
+    let costs: Vec<usize> = if threads <= 1 || members.len() < 2 {
+        costs_in_chunk(members)
+    } else {
+        let chunk_size = members.len().div_ceil(threads);
+        std::thread::scope(|scope| {
+            let handles = members
+                .chunks(chunk_size)
+                .map(|chunk| scope.spawn(move || costs_in_chunk(chunk)))
+                .collect::<Vec<_>>();
+            handles
+                .into_iter()
+                .flat_map(|handle| handle.join().unwrap())
+                .collect()
+        })
+    };
+    let current_cost = members
+        .iter()
+        .position(|member| *member == current)
+        .map(|i| costs[i]);
+    let (best, best_cost) = members
+        .iter()
+        .zip(costs.iter())
+        .min_by_key(|(_, cost)| **cost)
+        .expect("Clusters to update have members");
+    match current_cost {
+        Some(cost) if cost <= *best_cost => current,
+        _ => *best,
+    }
+}
+
+/// Partition unique sequences into k clusters around medoids. Initial medoids
+/// are chosen as in k-means++, with each sequence chosen with probability
+/// proportional to its abundance times its squared distance to the closest
+/// medoid so far. Then sequences are assigned to their closest medoid, and
+/// each medoid is replaced by the member of its cluster with the smallest
+/// abundance-weighted total distance to the others, until the medoids do not
+/// change or max_iterations rounds of assignment have been done.
+fn k_medoids_cluster(
+    uniques: &[UniqueSequence],
+    k: usize,
+    max_iterations: usize,
+    options: &ClusterOptions,
+) -> Result<Clustering, Box<dyn Error>> {
+    if k == 0 {
+        return Err("Number of medoids must be at least 1".into());
+    }
+    let weight_masks = match uniques.first() {
+        Some(unique) => options.column_weights.to_masks(unique.encoding.len)?,
+        None => None,
+    };
+    if k > uniques.len() {
+        warn!(
+            "Asked for {} medoids, but there are only {} unique sequences",
+            k,
+            uniques.len()
+        );
+    }
+
+    let mut rng = SplitMix64(options.seed);
+    let mut medoids: Vec<usize> = vec![];
+    let mut closest_distances = vec![usize::MAX; uniques.len()];
+    while medoids.len() < k.min(uniques.len()) {
+        let weights = uniques
+            .iter()
+            .zip(closest_distances.iter())
+            .map(|(unique, distance)| match medoids.is_empty() {
+                true => unique.abundance as u64,
+                false => unique.abundance as u64 * (*distance as u64).pow(2),
+            })
+            .collect::<Vec<_>>();
+        // When all remaining sequences are identical to a medoid under the
+        // column weights, there is nothing to choose between.
+        let medoid = match rng.choose_weighted(&weights) {
+            Some(medoid) => medoid,
+            None => (0..uniques.len())
+                .find(|i| !medoids.contains(i))
+                .expect("Fewer medoids than uniques"),
+        };
+        for (unique, closest) in uniques.iter().zip(closest_distances.iter_mut()) {
+            let distance = uniques[medoid]
+                .encoding
+                .encoding
+                .distance(&unique.encoding.encoding, weight_masks.as_ref());
+            *closest = (*closest).min(distance);
+        }
+        medoids.push(medoid);
+    }
+
+    let mut iteration = 0;
+    loop {
+        let mut centroids = WindowSet::new(CURRENT_DB_VERSION);
+        for medoid in &medoids {
+            centroids.push_encoding(uniques[*medoid].encoding.clone());
+        }
+        let assignments =
+            closest_windows(&centroids, uniques, weight_masks.as_ref(), options.threads);
+        iteration += 1;
+
+        let mut members = vec![vec![]; medoids.len()];
+        for (unique_index, assignment) in assignments.iter().enumerate() {
+            if let Some((cluster_number, _)) = assignment {
+                members[*cluster_number].push(unique_index);
+            }
+        }
+        // A medoid identical to an earlier one under the column weights may
+        // have no members, and so is kept.
+        let new_medoids = medoids
+            .iter()
+            .zip(members.iter())
+            .map(|(medoid, members)| match members.is_empty() {
+                true => *medoid,
+                false => cluster_medoid(
+                    members,
+                    *medoid,
+                    uniques,
+                    weight_masks.as_ref(),
+                    options.threads,
+                ),
+            })
+            .collect::<Vec<_>>();
+        debug!(
+            "K-medoids iteration {} medoids: {:?}",
+            iteration, new_medoids
+        );
+        if new_medoids == medoids || iteration >= max_iterations {
+            if new_medoids != medoids {
+                warn!(
+                    "K-medoids clustering did not converge after {} iterations",
+                    max_iterations
+                );
+            }
+            return Ok(Clustering {
+                centroids,
+                centroid_uniques: medoids.into_iter().map(Some).collect(),
+                assignments,
+                reassigned: None,
+                weight_masks,
+            });
+        }
+        medoids = new_medoids;
+    }
+}
+
+/// Read the windows of a reference database, which must all be the same
+/// length.
+pub(crate) fn load_reference(db_path: &Path) -> Result<WindowSet, Box<dyn Error>> {
+    let mut targets = load_targets(db_path)?;
+    if targets.len() != 1 {
+        return Err(format!(
+            "Reference database {} must contain windows of a single length, but has {} lengths",
+            db_path.display(),
+            targets.len()
+        )
+        .into());
+    }
+    Ok(targets.pop().unwrap().windows)
+}
+
+/// Assign each unique sequence in turn to the first closest centroid within
+/// the max divergence. If there is none, the sequence becomes a new centroid,
+/// or is left unassigned when clustering against a closed reference.
+fn greedy_cluster(
+    uniques: &[UniqueSequence],
+    reference: Option<WindowSet>,
+    options: &ClusterOptions,
+) -> Result<Clustering, Box<dyn Error>> {
+    let max_divergence_usize = options.max_divergence as usize;
 
-use crate::{SeqEncodingLength, WindowSet};
+    // The centroids can be written out as a database, so are versioned as one
+    let mut centroids = reference.unwrap_or_else(|| WindowSet::new(CURRENT_DB_VERSION));
+    let mut centroid_uniques = vec![None; centroids.windows.len()];
+    let mut assignments = Vec::with_capacity(uniques.len());
+
+    let weight_masks = match uniques.first() {
+        Some(unique) => {
+            if let Some(len) = centroids.len.map(NonZeroUsize::get) {
+                if len != unique.encoding.len {
+                    return Err(format!(
+                        "Reference windows are length {}, but input sequences are length {}",
+                        len, unique.encoding.len
+                    )
+                    .into());
+                }
+            }
+            options.column_weights.to_masks(unique.encoding.len)?
+        }
+        None => None,
+    };
+
+    // Sequences are compared to the centroids existing at the start of their
+    // batch in parallel, and then to the centroids created earlier in the
+    // batch in order, giving the same result for any number of threads.
+    let threads = options.threads.max(1);
+    let mut batch_start = 0;
+    for batch in uniques.chunks(BATCH_SIZE_PER_THREAD * threads) {
+        let num_batch_start_centroids = centroids.windows.len();
+        let closest = closest_windows(&centroids, batch, weight_masks.as_ref(), threads);
+
+        for (batch_index, (unique, closest)) in batch.iter().zip(closest).enumerate() {
+            // New centroids have larger numbers, so are only the first
+            // closest if strictly closer.
+            let mut best = closest;
+            for (i, window) in centroids
+                .windows
+                .iter()
+                .enumerate()
+                .skip(num_batch_start_centroids)
+            {
+                let distance = window.distance(&unique.encoding.encoding, weight_masks.as_ref());
+                let closer = match best {
+                    Some((_, best_distance)) => distance < best_distance,
+                    None => true,
+                };
+                if closer {
+                    best = Some((i, distance));
+                }
+            }
+
+            let assignment = match best.filter(|(_, distance)| *distance <= max_divergence_usize) {
+                Some(best) => Some(best),
+                None if options.closed_reference => None,
+                None => {
+                    // If distance > max_divergence then add to new centroid
+                    centroids.push_encoding(unique.encoding.clone());
+                    centroid_uniques.push(Some(batch_start + batch_index));
+                    Some((centroids.windows.len() - 1, 0))
+                }
+            };
+            debug!("Assigned centroid: {:?}", assignment);
+            debug!("windows len: {}", centroids.windows.len());
+            assignments.push(assignment);
+        }
+        batch_start += batch.len();
+    }
+    Ok(Clustering {
+        centroids,
+        centroid_uniques,
+        assignments,
+        reassigned: None,
+        weight_masks,
+    })
+}
+
+/// Write the identifier, cluster number, centroid identifier and distance to
+/// the centroid of every input sequence in input order, and whether it is a
+/// duplicate of an earlier sequence. Unassigned sequences have NA in place of
+/// the cluster, centroid and distance. If sequences were reassigned, whether
+/// the cluster of each changed is also written.
+fn write_membership(
+    dereplicated: &Dereplicated,
+    clustering: &Clustering,
+    writer: &mut dyn Write,
+) -> Result<(), Box<dyn Error>> {
+    let mut seen = vec![false; dereplicated.uniques.len()];
+    for (id, unique_index) in &dereplicated.records {
+        match clustering.assignments[*unique_index] {
+            Some((cluster_number, distance)) => write!(
+                writer,
+                "{}\t{}\t{}\t{}\t{}",
+                id,
+                cluster_number,
+                clustering.centroid_id(cluster_number, &dereplicated.uniques),
+                distance,
+                seen[*unique_index]
+            )?,
+            None => write!(writer, "{}\tNA\tNA\tNA\t{}", id, seen[*unique_index])?,
+        }
+        if let Some(reassigned) = &clustering.reassigned {
+            write!(writer, "\t{}", reassigned[*unique_index])?;
+        }
+        writeln!(writer)?;
+        seen[*unique_index] = true;
+    }
+    Ok(())
+}
 
 pub fn cluster(
     input_fasta: &Path,
-    max_divergence: u32,
-    print_stream: &mut dyn std::io::Write,
+    options: &ClusterOptions,
+    print_stream: &mut dyn Write,
 ) -> Result<(), Box<dyn Error>> {
     let start = Instant::now();
-    let max_divergence_usize = max_divergence as usize;
-
-    // Create vec of centroids - version not used, so zero
-    let mut centroids = WindowSet::new(0);
 
-    let mut seen_sequences = HashSet::<Vec<u64>>::new();
-
-    // Iterate input sequences
-    // Open the query file as a fasta file.
-    let mut query_reader = parse_fastx_file(input_fasta).expect("valid path/file of input fasta");
+    let reference = match &options.reference_db {
+        Some(db_path) => Some(load_reference(db_path)?),
+        None => None,
+    };
+    if options.closed_reference && reference.is_none() {
+        return Err("Closed reference clustering requires a reference database".into());
+    }
+    if !options.coarser_divergences.is_empty() && options.hierarchy_output.is_none() {
+        return Err("Clustering at multiple divergences requires a hierarchy output".into());
+    }
+    if options.single_linkage && (options.reference_db.is_some() || options.reassign) {
+        return Err(
+            "Single linkage clustering cannot be used with a reference database or reassignment"
+                .into(),
+        );
+    }
+    if options.k_medoids.is_some()
+        && (options.single_linkage
+            || options.reference_db.is_some()
+            || options.reassign
+            || !options.coarser_divergences.is_empty())
+    {
+        return Err(
+            "K-medoids clustering cannot be used with single linkage, a reference database, reassignment or multiple divergences"
+                .into(),
+        );
+    }
+    if options
+        .small_cluster_max_divergence
+        .is_some_and(|max_divergence| max_divergence < options.max_divergence)
+    {
+        return Err(
+            "The max divergence for members of small clusters must be at least the max divergence"
+                .into(),
+        );
+    }
+    let mut previous_divergence = options.max_divergence;
+    for max_divergence in &options.coarser_divergences {
+        if *max_divergence <= previous_divergence {
+            return Err("Max divergences must be given in increasing order".into());
+        }
+        previous_divergence = *max_divergence;
+    }
 
-    // Pre-initialise the distances vector so don't have to continually reallocate.
-    let mut distances = vec![];
+    let mut dereplicated = dereplicate(input_fasta)?;
+    if options.sort_by_abundance {
+        dereplicated.sort_by_abundance();
+    }
+    let uniques = &dereplicated.uniques;
+    if let Some(unique) = uniques.first() {
+        options
+            .column_weights
+            .check_masked_columns(unique.encoding.len)?;
+    }
 
     info!("Clustering ..");
-    let mut query_number: u32 = 0;
-    while let Some(record) = query_reader.next() {
-        query_number += 1;
+    let mut clustering = if let Some(k) = options.k_medoids {
+        k_medoids_cluster(uniques, k, MAX_K_MEDOIDS_ITERATIONS, options)?
+    } else if options.single_linkage {
+        single_linkage_cluster(uniques, options)?
+    } else {
+        greedy_cluster(uniques, reference, options)?
+    };
+    if options.reassign {
+        info!("Reassigning sequences to their closest centroid ..");
+        clustering.reassign(uniques, options.threads);
+    }
+    if options.min_cluster_size > 1 {
+        clustering.remove_small_clusters(
+            uniques,
+            options.min_cluster_size,
+            options.small_cluster_max_divergence,
+        );
+    }
 
-        // Encode as vec of bools
-        let record_unwrapped = record.expect("Failed to parse input sequence");
-        let seq = record_unwrapped.seq();
-        //let query_vec = seq.iter().map(|c| encode_single(*c)).collect::<Vec<_>>();
-        let query_vec =
-            SeqEncodingLength::from_bytes(record_unwrapped.id(), &record_unwrapped.seq());
+    // Print each assigned unique sequence and the centroid it belongs to
+    for (unique, assignment) in uniques.iter().zip(clustering.assignments.iter()) {
+        if let Some((cluster_number, _)) = assignment {
+            writeln!(
+                print_stream,
+                "{}\t{}",
+                std::str::from_utf8(&unique.seq).unwrap(),
+                clustering.centroids.get_as_string(*cluster_number)
+            )?;
+        }
+    }
 
-        // Skip if sequence has already been seen (in which case insert returns false)
-        if !seen_sequences.insert(query_vec.encoding.0.clone()) {
-            continue;
+    if let Some(path) = &options.cluster_sizes_output {
+        let mut writer = BufWriter::new(File::create(path)?);
+        for (cluster_number, size) in clustering.cluster_sizes(uniques).iter().enumerate() {
+            writeln!(
+                writer,
+                "{}\t{}\t{}",
+                cluster_number,
+                clustering.centroids.get_as_string(cluster_number),
+                size
+            )?;
         }
+        writer.flush()?;
+    }
 
-        // Get distances
-        centroids.get_distances(&query_vec, &mut distances);
+    let consensus = if options.consensus {
+        info!("Calculating cluster consensus sequences ..");
+        Some(consensus_sequences(&clustering, uniques))
+    } else {
+        None
+    };
 
-        // Find min distance and index of it
-        let min_distance = if distances.is_empty() {
-            max_divergence_usize * 2 + 2
-        } else {
-            *(distances.iter().min().unwrap())
-        };
+    if let Some(path) = &options.centroids_output {
+        let mut writer = BufWriter::new(File::create(path)?);
+        for (cluster_number, size) in clustering.cluster_sizes(uniques).iter().enumerate() {
+            writeln!(
+                writer,
+                ">{};size={}",
+                clustering.centroid_id(cluster_number, uniques),
+                size
+            )?;
+            match &consensus {
+                Some(consensus) => writer.write_all(&consensus[cluster_number].sequence)?,
+                None => write!(
+                    writer,
+                    "{}",
+                    clustering.centroid_sequence(cluster_number, uniques)
+                )?,
+            }
+            writeln!(writer)?;
+        }
+        writer.flush()?;
+    }
 
-        // If distance < max_divergence then add to centroid
-        let mut assigned_centroid = 0;
-        if min_distance <= max_divergence_usize {
-            for (i, distance) in distances.iter().enumerate() {
-                if *distance == min_distance {
-                    assigned_centroid = i;
-                    break;
+    if let Some(path) = &options.centroids_db {
+        info!("Writing centroids db file {}", path.display());
+        match &consensus {
+            Some(consensus) => {
+                let mut windows = WindowSet::new(CURRENT_DB_VERSION);
+                for (cluster_number, cluster_consensus) in consensus.iter().enumerate() {
+                    windows.push_encoding(SeqEncodingLength::from_bytes(
+                        clustering.centroid_id(cluster_number, uniques).as_bytes(),
+                        &cluster_consensus.sequence,
+                    ));
                 }
+                write_db(&windows, path)?;
+            }
+            None => write_db(&clustering.centroids, path)?,
+        }
+    }
+
+    if let (Some(path), Some(consensus)) = (&options.consensus_support_output, &consensus) {
+        let mut writer = BufWriter::new(File::create(path)?);
+        for (cluster_number, cluster_consensus) in consensus.iter().enumerate() {
+            let support = match &cluster_consensus.support {
+                Some(support) => support
+                    .iter()
+                    .map(|s| format!("{:.2}", s))
+                    .collect::<Vec<_>>()
+                    .join(","),
+                None => "NA".to_string(),
+            };
+            writeln!(
+                writer,
+                "{}\t{}\t{}",
+                cluster_number,
+                clustering.centroid_id(cluster_number, uniques),
+                support
+            )?;
+        }
+        writer.flush()?;
+    }
+
+    if let Some(path) = &options.membership_output {
+        let mut writer = BufWriter::new(File::create(path)?);
+        write_membership(&dereplicated, &clustering, &mut writer)?;
+        writer.flush()?;
+    }
+
+    if let Some(path) = &options.hierarchy_output {
+        let hierarchy = cluster_hierarchy(&clustering, uniques, options)?;
+        let mut writer = BufWriter::new(File::create(path)?);
+        write_hierarchy(&dereplicated, &clustering, &hierarchy, options, &mut writer)?;
+        writer.flush()?;
+    }
+
+    let mut num_unassigned = 0;
+    let mut unassigned_writer = match &options.unassigned_output {
+        Some(path) => Some(BufWriter::new(File::create(path)?)),
+        None => None,
+    };
+    for (id, unique_index) in &dereplicated.records {
+        if clustering.assignments[*unique_index].is_none() {
+            num_unassigned += 1;
+            if let Some(writer) = unassigned_writer.as_mut() {
+                writeln!(writer, ">{}", id)?;
+                writer.write_all(&uniques[*unique_index].seq)?;
+                writeln!(writer)?;
             }
-        } else {
-            // If distance >= max_divergence then add to new centroid
-            assigned_centroid = centroids.windows.len();
-            centroids.push_encoding(query_vec);
-            distances.push(0); // Adding another entry so that distances.len() == centroids.windows.len()
         }
-        debug!("Assigned centroid: {}", assigned_centroid);
-        debug!("windows len: {}", centroids.windows.len());
+    }
+    if let Some(writer) = unassigned_writer.as_mut() {
+        writer.flush()?;
+    }
 
-        // Print the sequence and the centroid it belongs to
-        writeln!(
-            print_stream,
-            "{}\t{}",
-            std::str::from_utf8(&seq).unwrap(),
-            centroids.get_as_string(assigned_centroid)
-        )?;
+    if let Some(path) = &options.stats_output {
+        let stats = ClusterStats::new(
+            &dereplicated,
+            &clustering,
+            num_unassigned,
+            start.elapsed().as_secs_f64(),
+        );
+        let mut writer = BufWriter::new(File::create(path)?);
+        serde_json::to_writer_pretty(&mut writer, &stats)?;
+        writeln!(writer)?;
+        writer.flush()?;
     }
+    print_stream.flush()?;
 
     info!(
-        "Clustering complete, took {} seconds. Clustered {} sequences into {} clusters.",
+        "Clustering complete, took {} seconds. Clustered {} sequences into {} clusters, leaving {} unassigned.",
         start.elapsed().as_secs(),
-        query_number,
-        centroids.windows.len()
+        dereplicated.records.len(),
+        clustering.centroids.windows.len(),
+        num_unassigned
     );
     Ok(())
 }
@@ -101,7 +1279,15 @@ mod tests {
     #[test]
     fn test_simple() {
         let mut stream = Cursor::new(Vec::new());
-        cluster(Path::new("tests/data/cluster_dummy1.fna"), 1, &mut stream).unwrap();
+        cluster(
+            Path::new("tests/data/cluster_dummy1.fna"),
+            &ClusterOptions {
+                max_divergence: 1,
+                ..Default::default()
+            },
+            &mut stream,
+        )
+        .unwrap();
         assert_eq!(
             "ATGC\tATGC
 ATGG\tATGC
@@ -114,7 +1300,15 @@ AAAA\tAAAA
     #[test]
     fn test_bug1() {
         let mut stream = Cursor::new(Vec::new());
-        cluster(Path::new("tests/data/cluster_bug1.fna"), 2, &mut stream).unwrap();
+        cluster(
+            Path::new("tests/data/cluster_bug1.fna"),
+            &ClusterOptions {
+                max_divergence: 2,
+                ..Default::default()
+            },
+            &mut stream,
+        )
+        .unwrap();
         assert_eq!(
             "ATGCAAAAA\tATGCAAAAA\n\
              ATAAAAAAA\tATGCAAAAA\n\
@@ -130,7 +1324,10 @@ AAAA\tAAAA
         let mut stream = Cursor::new(Vec::new());
         cluster(
             Path::new("tests/data/cluster_best_hit_changes.fna"),
-            2,
+            &ClusterOptions {
+                max_divergence: 2,
+                ..Default::default()
+            },
             &mut stream,
         )
         .unwrap();
@@ -141,4 +1338,601 @@ AAAA\tAAAA
             std::str::from_utf8(stream.get_ref()).unwrap()
         )
     }
+
+    #[test]
+    fn test_column_weights() {
+        let mut stream = Cursor::new(Vec::new());
+        cluster(
+            Path::new("tests/data/cluster_dummy1.fna"),
+            &ClusterOptions {
+                max_divergence: 2,
+                column_weights: ColumnWeights::new(vec![], Some(vec![1, 1, 1, 3])),
+                ..Default::default()
+            },
+            &mut stream,
+        )
+        .unwrap();
+        assert_eq!(
+            "ATGC\tATGC\n\
+             ATGG\tATGG\n\
+             AAAA\tAAAA\n",
+            std::str::from_utf8(stream.get_ref()).unwrap()
+        )
+    }
+
+    #[test]
+    fn test_sort_by_abundance() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let sizes = temp_dir.path().join("sizes.tsv");
+        let mut stream = Cursor::new(Vec::new());
+        cluster(
+            Path::new("tests/data/cluster_abundance.fna"),
+            &ClusterOptions {
+                max_divergence: 1,
+                cluster_sizes_output: Some(sizes.clone()),
+                ..Default::default()
+            },
+            &mut stream,
+        )
+        .unwrap();
+        assert_eq!(
+            "ATGG\tATGG\n\
+             ATGC\tATGG\n\
+             AAAA\tAAAA\n",
+            std::str::from_utf8(stream.get_ref()).unwrap()
+        );
+        assert_eq!(
+            "0\tATGG\t5\n1\tAAAA\t1\n",
+            std::fs::read_to_string(&sizes).unwrap()
+        );
+
+        // The ATGC sequence has an abundance of 4 so becomes the centroid.
+        let mut stream = Cursor::new(Vec::new());
+        cluster(
+            Path::new("tests/data/cluster_abundance.fna"),
+            &ClusterOptions {
+                max_divergence: 1,
+                sort_by_abundance: true,
+                cluster_sizes_output: Some(sizes.clone()),
+                ..Default::default()
+            },
+            &mut stream,
+        )
+        .unwrap();
+        assert_eq!(
+            "ATGC\tATGC\n\
+             ATGG\tATGC\n\
+             AAAA\tAAAA\n",
+            std::str::from_utf8(stream.get_ref()).unwrap()
+        );
+        assert_eq!(
+            "0\tATGC\t5\n1\tAAAA\t1\n",
+            std::fs::read_to_string(&sizes).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_membership_output() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let membership = temp_dir.path().join("membership.tsv");
+        let mut stream = Cursor::new(Vec::new());
+        cluster(
+            Path::new("tests/data/cluster_abundance.fna"),
+            &ClusterOptions {
+                max_divergence: 1,
+                sort_by_abundance: true,
+                membership_output: Some(membership.clone()),
+                ..Default::default()
+            },
+            &mut stream,
+        )
+        .unwrap();
+        assert_eq!(
+            "seq1\t0\tseq2\t1\tfalse\n\
+             seq2\t0\tseq2\t0\tfalse\n\
+             seq3\t1\tseq3\t0\tfalse\n\
+             seq4\t0\tseq2\t0\ttrue\n",
+            std::fs::read_to_string(&membership).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_centroids_output() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let centroids = temp_dir.path().join("centroids.fna");
+        let centroids_db = temp_dir.path().join("centroids.smafadb");
+        let mut stream = Cursor::new(Vec::new());
+        cluster(
+            Path::new("tests/data/cluster_abundance.fna"),
+            &ClusterOptions {
+                max_divergence: 1,
+                sort_by_abundance: true,
+                centroids_output: Some(centroids.clone()),
+                centroids_db: Some(centroids_db.clone()),
+                ..Default::default()
+            },
+            &mut stream,
+        )
+        .unwrap();
+        assert_eq!(
+            ">seq2;size=5\nATGC\n>seq3;size=1\nAAAA\n",
+            std::fs::read_to_string(&centroids).unwrap()
+        );
+
+        let targets = crate::load_targets(&centroids_db).unwrap();
+        assert_eq!(1, targets.len());
+        assert_eq!(CURRENT_DB_VERSION, targets[0].windows.version);
+        assert_eq!("ATGC", targets[0].windows.get_as_string(0));
+        assert_eq!("AAAA", targets[0].windows.get_as_string(1));
+    }
+
+    #[test]
+    fn test_reference_clustering() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let reference_db = temp_dir.path().join("reference.smafadb");
+        crate::makedb(Path::new("tests/data/cluster_reference.fna"), &reference_db).unwrap();
+        let centroids = temp_dir.path().join("centroids.fna");
+
+        // Open reference, AAAA starts a new cluster after the reference ones.
+        let mut stream = Cursor::new(Vec::new());
+        cluster(
+            Path::new("tests/data/cluster_abundance.fna"),
+            &ClusterOptions {
+                max_divergence: 1,
+                reference_db: Some(reference_db.clone()),
+                centroids_output: Some(centroids.clone()),
+                ..Default::default()
+            },
+            &mut stream,
+        )
+        .unwrap();
+        assert_eq!(
+            "ATGG\tATGC\n\
+             ATGC\tATGC\n\
+             AAAA\tAAAA\n",
+            std::str::from_utf8(stream.get_ref()).unwrap()
+        );
+        assert_eq!(
+            ">reference_0;size=0\nTTTT\n\
+             >reference_1;size=5\nATGC\n\
+             >seq3;size=1\nAAAA\n",
+            std::fs::read_to_string(&centroids).unwrap()
+        );
+
+        // Closed reference, AAAA is unassigned.
+        let membership = temp_dir.path().join("membership.tsv");
+        let unassigned = temp_dir.path().join("unassigned.fna");
+        let mut stream = Cursor::new(Vec::new());
+        cluster(
+            Path::new("tests/data/cluster_abundance.fna"),
+            &ClusterOptions {
+                max_divergence: 1,
+                reference_db: Some(reference_db),
+                closed_reference: true,
+                membership_output: Some(membership.clone()),
+                unassigned_output: Some(unassigned.clone()),
+                ..Default::default()
+            },
+            &mut stream,
+        )
+        .unwrap();
+        assert_eq!(
+            "ATGG\tATGC\n\
+             ATGC\tATGC\n",
+            std::str::from_utf8(stream.get_ref()).unwrap()
+        );
+        assert_eq!(
+            "seq1\t1\treference_1\t1\tfalse\n\
+             seq2\t1\treference_1\t0\tfalse\n\
+             seq3\tNA\tNA\tNA\tfalse\n\
+             seq4\t1\treference_1\t0\ttrue\n",
+            std::fs::read_to_string(&membership).unwrap()
+        );
+        assert_eq!(
+            ">seq3\nAAAA\n",
+            std::fs::read_to_string(&unassigned).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_reassign() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let membership = temp_dir.path().join("membership.tsv");
+        let mut stream = Cursor::new(Vec::new());
+        cluster(
+            Path::new("tests/data/cluster_best_hit_changes.fna"),
+            &ClusterOptions {
+                max_divergence: 2,
+                reassign: true,
+                membership_output: Some(membership.clone()),
+                ..Default::default()
+            },
+            &mut stream,
+        )
+        .unwrap();
+        // seq2 is closer to seq3, which became a centroid after it.
+        assert_eq!(
+            "ATGCAAAAA\tATGCAAAAA\n\
+             ATAAAAAAA\tTTAAAAAAA\n\
+             TTAAAAAAA\tTTAAAAAAA\n",
+            std::str::from_utf8(stream.get_ref()).unwrap()
+        );
+        assert_eq!(
+            "seq1\t0\tseq1\t0\tfalse\tfalse\n\
+             seq2\t1\tseq3\t1\tfalse\ttrue\n\
+             seq3\t1\tseq3\t0\tfalse\tfalse\n\
+             seq4\t1\tseq3\t1\ttrue\ttrue\n",
+            std::fs::read_to_string(&membership).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_hierarchy() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let hierarchy = temp_dir.path().join("hierarchy.tsv");
+        let mut stream = Cursor::new(Vec::new());
+        cluster(
+            Path::new("tests/data/cluster_hierarchy.fna"),
+            &ClusterOptions {
+                max_divergence: 0,
+                coarser_divergences: vec![1, 2],
+                hierarchy_output: Some(hierarchy.clone()),
+                ..Default::default()
+            },
+            &mut stream,
+        )
+        .unwrap();
+        // s3 is within 2 of s1 but only becomes part of its cluster when the
+        // level 1 centroids are clustered.
+        assert_eq!(
+            "read\tmax_divergence_0\tmax_divergence_1\tmax_divergence_2\n\
+             s1\t0\t0\t0\n\
+             s2\t1\t0\t0\n\
+             s3\t2\t1\t0\n\
+             s4\t3\t2\t1\n\
+             s5\t4\t2\t1\n",
+            std::fs::read_to_string(&hierarchy).unwrap()
+        );
+
+        let result = cluster(
+            Path::new("tests/data/cluster_hierarchy.fna"),
+            &ClusterOptions {
+                max_divergence: 1,
+                coarser_divergences: vec![1],
+                hierarchy_output: Some(hierarchy),
+                ..Default::default()
+            },
+            &mut Cursor::new(Vec::new()),
+        );
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_segment_masks() {
+        // Masked column 1 is not in any segment.
+        assert_eq!(
+            vec![vec![0b11111], vec![0b11111 << 10 | 0b11111 << 15]],
+            segment_masks(&[1, 0, 2, 1], 2)
+        );
+        assert_eq!(
+            vec![vec![0b11111], vec![0]],
+            segment_masks(&[1, 0, 0, 0], 3)
+        );
+    }
+
+    #[test]
+    fn test_single_linkage() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let hierarchy = temp_dir.path().join("hierarchy.tsv");
+        let mut stream = Cursor::new(Vec::new());
+        cluster(
+            Path::new("tests/data/cluster_hierarchy.fna"),
+            &ClusterOptions {
+                max_divergence: 1,
+                single_linkage: true,
+                coarser_divergences: vec![4],
+                hierarchy_output: Some(hierarchy.clone()),
+                ..Default::default()
+            },
+            &mut stream,
+        )
+        .unwrap();
+        // AAAACC is 2 from AAAAAA but joined to it through AAAAAC.
+        assert_eq!(
+            "AAAAAA\tAAAAAA\n\
+             AAAAAC\tAAAAAA\n\
+             AAAACC\tAAAAAA\n\
+             CCCCCC\tCCCCCC\n\
+             CCCCCA\tCCCCCC\n",
+            std::str::from_utf8(stream.get_ref()).unwrap()
+        );
+        // AAAACC is 4 from CCCCCC, joining all sequences.
+        assert_eq!(
+            "read\tmax_divergence_1\tmax_divergence_4\n\
+             s1\t0\t0\n\
+             s2\t0\t0\n\
+             s3\t0\t0\n\
+             s4\t1\t0\n\
+             s5\t1\t0\n",
+            std::fs::read_to_string(&hierarchy).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_threads_give_same_result() {
+        // Enough variants of one sequence to span several batches.
+        let temp_dir = tempfile::tempdir().unwrap();
+        let fasta = temp_dir.path().join("variants.fna");
+        let mut state: u64 = 1;
+        let mut contents = String::new();
+        for i in 0..2000 {
+            let mut seq = b"ATGCATGCATGCATGCATGC".to_vec();
+            for _ in 0..4 {
+                state = state.wrapping_mul(6364136223846793005).wrapping_add(1);
+                seq[(state >> 33) as usize % 20] = b"ACGT"[(state >> 60) as usize % 4];
+            }
+            contents.push_str(&format!(">{}\n{}\n", i, std::str::from_utf8(&seq).unwrap()));
+        }
+        std::fs::write(&fasta, contents).unwrap();
+
+        let outputs = [1, 3, 8]
+            .iter()
+            .map(|threads| {
+                let membership = temp_dir.path().join(format!("membership{}.tsv", threads));
+                let mut stream = Cursor::new(Vec::new());
+                cluster(
+                    &fasta,
+                    &ClusterOptions {
+                        max_divergence: 2,
+                        reassign: true,
+                        membership_output: Some(membership.clone()),
+                        threads: *threads,
+                        ..Default::default()
+                    },
+                    &mut stream,
+                )
+                .unwrap();
+                (
+                    stream.into_inner(),
+                    std::fs::read_to_string(membership).unwrap(),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(outputs[0], outputs[1]);
+        assert_eq!(outputs[0], outputs[2]);
+    }
+
+    #[test]
+    fn test_consensus() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let centroids = temp_dir.path().join("centroids.fna");
+        let support = temp_dir.path().join("support.tsv");
+        let mut stream = Cursor::new(Vec::new());
+        cluster(
+            Path::new("tests/data/cluster_consensus.fna"),
+            &ClusterOptions {
+                max_divergence: 2,
+                centroids_output: Some(centroids.clone()),
+                consensus: true,
+                consensus_support_output: Some(support.clone()),
+                ..Default::default()
+            },
+            &mut stream,
+        )
+        .unwrap();
+        // The first seen centroid a has a C not shared by most of its members,
+        // and the last column of the second cluster is tied.
+        assert_eq!(
+            ">a;size=6\nATGG\n>e;size=2\nCCCN\n",
+            std::fs::read_to_string(&centroids).unwrap()
+        );
+        assert_eq!(
+            "0\ta\t0.67,1.00,1.00,0.83\n1\te\t1.00,1.00,1.00,0.50\n",
+            std::fs::read_to_string(&support).unwrap()
+        );
+
+        // The gap, N and R vote together, as do T and U.
+        cluster(
+            Path::new("tests/data/cluster_consensus_ambiguous.fna"),
+            &ClusterOptions {
+                max_divergence: 2,
+                centroids_output: Some(centroids.clone()),
+                consensus: true,
+                consensus_support_output: Some(support.clone()),
+                ..Default::default()
+            },
+            &mut Cursor::new(Vec::new()),
+        )
+        .unwrap();
+        assert_eq!(
+            ">x1;size=3\nAG-T\n",
+            std::fs::read_to_string(&centroids).unwrap()
+        );
+        assert_eq!(
+            "0\tx1\t1.00,0.67,1.00,0.67\n",
+            std::fs::read_to_string(&support).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_stats_output() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let stats = temp_dir.path().join("stats.json");
+        cluster(
+            Path::new("tests/data/cluster_abundance.fna"),
+            &ClusterOptions {
+                max_divergence: 1,
+                sort_by_abundance: true,
+                stats_output: Some(stats.clone()),
+                ..Default::default()
+            },
+            &mut Cursor::new(Vec::new()),
+        )
+        .unwrap();
+        let mut json: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&stats).unwrap()).unwrap();
+        assert!(json["runtime_seconds"].as_f64().unwrap() >= 0.0);
+        json["runtime_seconds"] = serde_json::json!(0.0);
+        assert_eq!(
+            serde_json::json!({
+                "num_sequences": 4,
+                "num_unique_sequences": 3,
+                "num_duplicates": 1,
+                "num_unassigned": 0,
+                "num_clusters": 2,
+                "num_singletons": 1,
+                "cluster_size_distribution": [
+                    {"size": 1, "num_clusters": 1},
+                    {"size": 5, "num_clusters": 1},
+                ],
+                "clusters": [
+                    {"cluster": 0, "centroid": "seq2", "size": 5, "mean_distance": 0.2, "max_distance": 1},
+                    {"cluster": 1, "centroid": "seq3", "size": 1, "mean_distance": 0.0, "max_distance": 0},
+                ],
+                "runtime_seconds": 0.0,
+            }),
+            json
+        );
+    }
+
+    #[test]
+    fn test_min_cluster_size() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let membership = temp_dir.path().join("membership.tsv");
+        let centroids = temp_dir.path().join("centroids.fna");
+        let unassigned = temp_dir.path().join("unassigned.fna");
+        let mut stream = Cursor::new(Vec::new());
+        cluster(
+            Path::new("tests/data/cluster_abundance.fna"),
+            &ClusterOptions {
+                max_divergence: 0,
+                min_cluster_size: 2,
+                membership_output: Some(membership.clone()),
+                centroids_output: Some(centroids.clone()),
+                unassigned_output: Some(unassigned.clone()),
+                ..Default::default()
+            },
+            &mut stream,
+        )
+        .unwrap();
+        assert_eq!(
+            "ATGC\tATGC\n",
+            std::str::from_utf8(stream.get_ref()).unwrap()
+        );
+        assert_eq!(
+            ">seq2;size=4\nATGC\n",
+            std::fs::read_to_string(&centroids).unwrap()
+        );
+        assert_eq!(
+            ">seq1\nATGG\n>seq3\nAAAA\n",
+            std::fs::read_to_string(&unassigned).unwrap()
+        );
+
+        // ATGG is within 1 of the remaining centroid, but AAAA is not.
+        cluster(
+            Path::new("tests/data/cluster_abundance.fna"),
+            &ClusterOptions {
+                max_divergence: 0,
+                min_cluster_size: 2,
+                small_cluster_max_divergence: Some(1),
+                membership_output: Some(membership.clone()),
+                centroids_output: Some(centroids.clone()),
+                ..Default::default()
+            },
+            &mut Cursor::new(Vec::new()),
+        )
+        .unwrap();
+        assert_eq!(
+            ">seq2;size=5\nATGC\n",
+            std::fs::read_to_string(&centroids).unwrap()
+        );
+        assert_eq!(
+            "seq1\t0\tseq2\t1\tfalse\n\
+             seq2\t0\tseq2\t0\tfalse\n\
+             seq3\tNA\tNA\tNA\tfalse\n\
+             seq4\t0\tseq2\t0\ttrue\n",
+            std::fs::read_to_string(&membership).unwrap()
+        );
+
+        // Moving ATGG out of its removed cluster counts as reassigning it.
+        cluster(
+            Path::new("tests/data/cluster_abundance.fna"),
+            &ClusterOptions {
+                max_divergence: 0,
+                reassign: true,
+                min_cluster_size: 2,
+                small_cluster_max_divergence: Some(1),
+                membership_output: Some(membership.clone()),
+                ..Default::default()
+            },
+            &mut Cursor::new(Vec::new()),
+        )
+        .unwrap();
+        assert_eq!(
+            "seq1\t0\tseq2\t1\tfalse\ttrue\n\
+             seq2\t0\tseq2\t0\tfalse\tfalse\n\
+             seq3\tNA\tNA\tNA\tfalse\tfalse\n\
+             seq4\t0\tseq2\t0\ttrue\tfalse\n",
+            std::fs::read_to_string(&membership).unwrap()
+        );
+
+        assert!(cluster(
+            Path::new("tests/data/cluster_abundance.fna"),
+            &ClusterOptions {
+                max_divergence: 2,
+                min_cluster_size: 2,
+                small_cluster_max_divergence: Some(1),
+                ..Default::default()
+            },
+            &mut Cursor::new(Vec::new()),
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_k_medoids() {
+        let dereplicated = dereplicate(Path::new("tests/data/cluster_medoids.fna")).unwrap();
+        let options = ClusterOptions {
+            seed: 0,
+            ..Default::default()
+        };
+        // Each group of three sequences forms a cluster around its middle
+        // sequence, though the second initial medoid is not a middle sequence.
+        let clustering =
+            k_medoids_cluster(&dereplicated.uniques, 2, MAX_K_MEDOIDS_ITERATIONS, &options)
+                .unwrap();
+        assert_eq!(vec![Some(1), Some(4)], clustering.centroid_uniques);
+        assert_eq!(
+            vec![
+                Some((0, 1)),
+                Some((0, 0)),
+                Some((0, 1)),
+                Some((1, 1)),
+                Some((1, 0)),
+                Some((1, 1))
+            ],
+            clustering.assignments
+        );
+        // Stopping after the first assignment leaves the initial medoids.
+        let clustering = k_medoids_cluster(&dereplicated.uniques, 2, 1, &options).unwrap();
+        assert_eq!(vec![Some(1), Some(5)], clustering.centroid_uniques);
+        assert_eq!(Some((1, 2)), clustering.assignments[3]);
+
+        // Asking for more medoids than sequences makes each its own medoid.
+        let mut stream = Cursor::new(Vec::new());
+        cluster(
+            Path::new("tests/data/cluster_medoids.fna"),
+            &ClusterOptions {
+                k_medoids: Some(9),
+                ..Default::default()
+            },
+            &mut stream,
+        )
+        .unwrap();
+        assert_eq!(
+            "AAAAAA\tAAAAAA\nAAAAAC\tAAAAAC\nAAAACC\tAAAACC\n\
+             CCCCCC\tCCCCCC\nCCCCCA\tCCCCCA\nCCCCAA\tCCCCAA\n",
+            std::str::from_utf8(stream.get_ref()).unwrap()
+        );
+    }
 }
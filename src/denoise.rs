@@ -0,0 +1,176 @@
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::time::Instant;
+
+use log::{debug, info};
+
+use crate::derep::dereplicate;
+use crate::{ColumnWeights, WindowSet, CURRENT_DB_VERSION};
+
+#[derive(Debug, Clone)]
+pub struct DenoiseOptions {
+    /// How quickly the abundance skew required to absorb a sequence into a
+    /// more abundant variant falls with the distance between them.
+    pub alpha: f64,
+    /// Sequences less abundant than this cannot become variants.
+    pub min_size: usize,
+    pub column_weights: ColumnWeights,
+}
+
+impl Default for DenoiseOptions {
+    fn default() -> Self {
+        DenoiseOptions {
+            alpha: 2.0,
+            min_size: 8,
+            column_weights: ColumnWeights::default(),
+        }
+    }
+}
+
+/// The largest abundance skew, i.e. the abundance of a sequence divided by
+/// that of a variant, at which the sequence is considered an error derived
+/// from the variant, as in UNOISE.
+fn max_skew(alpha: f64, distance: usize) -> f64 {
+    0.5f64.powf(alpha * distance as f64 + 1.0)
+}
+
+/// Denoise sequences into exact sequence variants, following UNOISE. Unique
+/// sequences are considered in order of decreasing abundance, and each is
+/// absorbed into the most abundant existing variant it could have been derived
+/// from by sequencing error. Otherwise, it becomes a new variant if it is
+/// abundant enough, or is discarded.
+///
+/// Variants are written with the total abundance of the sequences absorbed
+/// into them as a ";size=N" annotation. If given, a mapping file is also
+/// written with the identifier of each input sequence and its variant, or NA
+/// if it was discarded.
+pub fn denoise(
+    input: &Path,
+    options: &DenoiseOptions,
+    output: &mut dyn Write,
+    mapping_output: Option<&Path>,
+) -> Result<(), Box<dyn Error>> {
+    let start = Instant::now();
+    let mut dereplicated = dereplicate(input)?;
+    dereplicated.sort_by_abundance();
+    let uniques = &dereplicated.uniques;
+
+    let weight_masks = match uniques.first() {
+        Some(unique) => {
+            options
+                .column_weights
+                .check_masked_columns(unique.encoding.len)?;
+            options.column_weights.to_masks(unique.encoding.len)?
+        }
+        None => None,
+    };
+
+    info!("Denoising ..");
+    let mut variants = WindowSet::new(CURRENT_DB_VERSION);
+    let mut variant_uniques: Vec<usize> = vec![];
+    let mut assignments: Vec<Option<usize>> = Vec::with_capacity(uniques.len());
+    let mut distances = vec![];
+    for (unique_index, unique) in uniques.iter().enumerate() {
+        distances.resize(variants.windows.len(), 0);
+        variants.get_distances(&unique.encoding, weight_masks.as_ref(), &mut distances);
+
+        // Variants are in order of decreasing abundance, so the first found
+        // is the most abundant possible parent.
+        let parent =
+            distances
+                .iter()
+                .zip(variant_uniques.iter())
+                .position(|(distance, variant_unique)| {
+                    let skew = unique.abundance as f64 / uniques[*variant_unique].abundance as f64;
+                    skew <= max_skew(options.alpha, *distance)
+                });
+
+        let assignment = match parent {
+            Some(variant) => Some(variant),
+            None if unique.abundance >= options.min_size => {
+                variants.push_encoding(unique.encoding.clone());
+                variant_uniques.push(unique_index);
+                Some(variant_uniques.len() - 1)
+            }
+            None => None,
+        };
+        debug!("Assigned variant: {:?}", assignment);
+        assignments.push(assignment);
+    }
+
+    let mut sizes = vec![0; variant_uniques.len()];
+    for (unique, assignment) in uniques.iter().zip(assignments.iter()) {
+        if let Some(variant) = assignment {
+            sizes[*variant] += unique.abundance;
+        }
+    }
+    for (variant_unique, size) in variant_uniques.iter().zip(sizes) {
+        let variant = &uniques[*variant_unique];
+        writeln!(output, ">{};size={}", variant.id, size)?;
+        output.write_all(&variant.seq)?;
+        writeln!(output)?;
+    }
+
+    let mut num_discarded = 0;
+    let mut mapping_writer = match mapping_output {
+        Some(path) => Some(BufWriter::new(File::create(path)?)),
+        None => None,
+    };
+    for (id, unique_index) in &dereplicated.records {
+        let variant_id = match assignments[*unique_index] {
+            Some(variant) => uniques[variant_uniques[variant]].id.as_str(),
+            None => {
+                num_discarded += 1;
+                "NA"
+            }
+        };
+        if let Some(writer) = mapping_writer.as_mut() {
+            writeln!(writer, "{}\t{}", id, variant_id)?;
+        }
+    }
+    output.flush()?;
+    if let Some(writer) = mapping_writer.as_mut() {
+        writer.flush()?;
+    }
+
+    info!(
+        "Denoising complete, took {} seconds. Denoised {} sequences into {} variants, discarding {} sequences.",
+        start.elapsed().as_secs(),
+        dereplicated.records.len(),
+        variant_uniques.len(),
+        num_discarded
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_denoise() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let mapping = temp_dir.path().join("mapping.tsv");
+        let mut stream = Cursor::new(Vec::new());
+        denoise(
+            Path::new("tests/data/denoise.fna"),
+            &DenoiseOptions::default(),
+            &mut stream,
+            Some(&mapping),
+        )
+        .unwrap();
+        // e1 is 1 from v1 and much less abundant, so is absorbed. e2 is not
+        // skewed enough to be absorbed by v2, and too rare to be a variant.
+        assert_eq!(
+            ">v1;size=23\nATGCATGC\n>v2;size=10\nTTTTATGC\n",
+            std::str::from_utf8(stream.get_ref()).unwrap()
+        );
+        assert_eq!(
+            "v1\tv1\ne1\tv1\nv2\tv2\ne2\tNA\nv1b\tv1\n",
+            std::fs::read_to_string(mapping).unwrap()
+        );
+    }
+}
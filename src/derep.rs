@@ -0,0 +1,186 @@
+use needletail::parse_fastx_file;
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::time::Instant;
+
+use log::info;
+
+use crate::{sequence_identifier, SeqEncodingLength};
+
+/// A distinct sequence, represented by the first sequence seen with it.
+pub(crate) struct UniqueSequence {
+    pub id: String,
+    pub seq: Vec<u8>,
+    pub encoding: SeqEncodingLength,
+    /// Total abundance of the sequences identical to this one.
+    pub abundance: usize,
+}
+
+/// Input sequences collapsed into unique sequences.
+pub(crate) struct Dereplicated {
+    pub uniques: Vec<UniqueSequence>,
+    /// Identifier of each input sequence in input order, and the index of
+    /// its unique sequence.
+    pub records: Vec<(String, usize)>,
+}
+
+impl Dereplicated {
+    /// Order the unique sequences by decreasing abundance, keeping the input
+    /// order of sequences with the same abundance.
+    pub fn sort_by_abundance(&mut self) {
+        let mut indexed = std::mem::take(&mut self.uniques)
+            .into_iter()
+            .enumerate()
+            .collect::<Vec<_>>();
+        indexed.sort_by_key(|(_, unique)| std::cmp::Reverse(unique.abundance));
+        let mut new_index = vec![0; indexed.len()];
+        for (new, (old, _)) in indexed.iter().enumerate() {
+            new_index[*old] = new;
+        }
+        self.uniques = indexed.into_iter().map(|(_, unique)| unique).collect();
+        for (_, unique_index) in self.records.iter_mut() {
+            *unique_index = new_index[*unique_index];
+        }
+    }
+}
+
+/// Split a vsearch-style abundance annotation e.g. "seq1;size=5" into the
+/// identifier without the annotation, and the abundance.
+pub(crate) fn parse_size_annotation(id: &str) -> (String, Option<usize>) {
+    let mut size = None;
+    let mut fields = Vec::new();
+    for field in id.split(';') {
+        match field.strip_prefix("size=").map(|s| s.parse::<usize>()) {
+            Some(Ok(s)) => size = Some(s),
+            _ => fields.push(field),
+        }
+    }
+    let stripped = fields.join(";");
+    (stripped.trim_end_matches(';').to_string(), size)
+}
+
+/// Collapse identical sequences. The abundance of each input sequence is
+/// taken from its ";size=N" annotation, or 1 if there is none.
+pub(crate) fn dereplicate(input: &Path) -> Result<Dereplicated, Box<dyn Error>> {
+    let mut reader = parse_fastx_file(input).expect("valid path/file of input fastx");
+
+    let mut uniques: Vec<UniqueSequence> = Vec::new();
+    let mut records = Vec::new();
+    let mut unique_indices = HashMap::<Vec<u64>, usize>::new();
+    while let Some(record) = reader.next() {
+        let record = record.expect("Failed to parse input sequence");
+        let seq = record.seq();
+        let encoding = SeqEncodingLength::from_bytes(record.id(), &seq);
+        let (id, size) = parse_size_annotation(&sequence_identifier(record.id()));
+        let abundance = size.unwrap_or(1);
+
+        let unique_index = match unique_indices.get(&encoding.encoding.0) {
+            Some(&i) => {
+                uniques[i].abundance += abundance;
+                i
+            }
+            None => {
+                unique_indices.insert(encoding.encoding.0.clone(), uniques.len());
+                uniques.push(UniqueSequence {
+                    id: id.clone(),
+                    seq: seq.into_owned(),
+                    encoding,
+                    abundance,
+                });
+                uniques.len() - 1
+            }
+        };
+        records.push((id, unique_index));
+    }
+    Ok(Dereplicated { uniques, records })
+}
+
+/// Write one representative of each unique sequence with its abundance as a
+/// ";size=N" annotation, most abundant first. If given, a mapping file is also
+/// written with the identifier of each input sequence and its representative.
+pub fn derep(
+    input: &Path,
+    output: &mut dyn Write,
+    mapping_output: Option<&Path>,
+) -> Result<(), Box<dyn Error>> {
+    let start = Instant::now();
+    info!("Dereplicating ..");
+    let mut dereplicated = dereplicate(input)?;
+    dereplicated.sort_by_abundance();
+
+    for unique in &dereplicated.uniques {
+        writeln!(output, ">{};size={}", unique.id, unique.abundance)?;
+        output.write_all(&unique.seq)?;
+        writeln!(output)?;
+    }
+
+    output.flush()?;
+
+    if let Some(mapping_output) = mapping_output {
+        let mut mapping_writer = BufWriter::new(File::create(mapping_output)?);
+        for (id, unique_index) in &dereplicated.records {
+            writeln!(
+                mapping_writer,
+                "{}\t{}",
+                id, dereplicated.uniques[*unique_index].id
+            )?;
+        }
+        mapping_writer.flush()?;
+    }
+
+    info!(
+        "Dereplication complete, took {} seconds. Collapsed {} sequences into {} unique sequences.",
+        start.elapsed().as_secs(),
+        dereplicated.records.len(),
+        dereplicated.uniques.len()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_parse_size_annotation() {
+        assert_eq!(
+            ("seq1".to_string(), Some(5)),
+            parse_size_annotation("seq1;size=5")
+        );
+        assert_eq!(
+            ("seq1".to_string(), Some(5)),
+            parse_size_annotation("seq1;size=5;")
+        );
+        assert_eq!(
+            ("seq1;sample=a".to_string(), Some(12)),
+            parse_size_annotation("seq1;size=12;sample=a")
+        );
+        assert_eq!(("seq1".to_string(), None), parse_size_annotation("seq1"));
+    }
+
+    #[test]
+    fn test_derep() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let mapping = temp_dir.path().join("mapping.tsv");
+        let mut stream = Cursor::new(Vec::new());
+        derep(
+            Path::new("tests/data/derep.fna"),
+            &mut stream,
+            Some(&mapping),
+        )
+        .unwrap();
+        assert_eq!(
+            ">seq2;size=4\nAAAA\n>seq1;size=2\nATGC\n>seq4;size=1\nTTTT\n",
+            std::str::from_utf8(stream.get_ref()).unwrap()
+        );
+        assert_eq!(
+            "seq1\tseq1\nseq2\tseq2\nseq3\tseq1\nseq4\tseq4\nseq5\tseq2\n",
+            std::fs::read_to_string(mapping).unwrap()
+        );
+    }
+}
@@ -0,0 +1,162 @@
+use needletail::parse_fastx_file;
+
+use std::error::Error;
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use log::info;
+
+use crate::{load_search_targets, ColumnWeights, SeqEncodingLength};
+
+#[derive(Debug, Clone, Default)]
+pub struct FilterOptions {
+    pub max_divergence: u32,
+    /// Keep reads which do not match instead of those that do.
+    pub inverse: bool,
+    pub column_weights: ColumnWeights,
+}
+
+/// Write the records of a FASTA/FASTQ file whose best hit in the databases is
+/// within the maximum divergence, or beyond it if inverse. Records are written
+/// in their original format, and records not the same length as any database
+/// are treated as having no match.
+pub fn filter(
+    db_paths: &[PathBuf],
+    input: &Path,
+    options: &FilterOptions,
+    output: &mut dyn std::io::Write,
+) -> Result<(), Box<dyn Error>> {
+    let start = Instant::now();
+    let (targets, weight_masks) = load_search_targets(db_paths, &options.column_weights)?;
+    let mut distances = targets
+        .iter()
+        .map(|target| vec![0; target.windows.windows.len()])
+        .collect::<Vec<_>>();
+
+    let mut reader = parse_fastx_file(input).expect("valid path/file of input fastx");
+
+    info!("Filtering ..");
+    let mut num_reads: u64 = 0;
+    let mut num_written: u64 = 0;
+    while let Some(record) = reader.next() {
+        let record = record.expect("Failed to parse input sequence");
+        let query_vec = SeqEncodingLength::from_bytes(record.id(), &record.seq());
+        num_reads += 1;
+
+        let mut matches = false;
+        for ((target, target_weight_masks), target_distances) in targets
+            .iter()
+            .zip(weight_masks.iter())
+            .zip(distances.iter_mut())
+        {
+            if target.windows.len.map(NonZeroUsize::get) != Some(query_vec.len) {
+                continue;
+            }
+            target.windows.get_distances(
+                &query_vec,
+                target_weight_masks.as_ref(),
+                target_distances,
+            );
+            if target_distances
+                .iter()
+                .any(|d| *d <= options.max_divergence as usize)
+            {
+                matches = true;
+                break;
+            }
+        }
+
+        if matches != options.inverse {
+            record.write(output, None)?;
+            num_written += 1;
+        }
+    }
+    output.flush()?;
+
+    info!(
+        "Filtering complete, took {} seconds. Wrote {} of {} reads.",
+        start.elapsed().as_secs(),
+        num_written,
+        num_reads
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_filter() {
+        let db_paths = [PathBuf::from("tests/data/random_3_2.fna.smafadb")];
+        let mut stream = Cursor::new(Vec::new());
+        filter(
+            &db_paths,
+            Path::new("tests/data/random_3_2_low_quality.fq"),
+            &FilterOptions {
+                max_divergence: 1,
+                ..Default::default()
+            },
+            &mut stream,
+        )
+        .unwrap();
+        assert_eq!(
+            "@q1\nCTG\n+\nII#\n@q2\nAGT\n+\n##I\n",
+            std::str::from_utf8(stream.get_ref()).unwrap()
+        );
+
+        // Both reads only differ from a subject in their last column.
+        let mut stream = Cursor::new(Vec::new());
+        filter(
+            &db_paths,
+            Path::new("tests/data/random_3_2_low_quality.fq"),
+            &FilterOptions {
+                max_divergence: 0,
+                ..Default::default()
+            },
+            &mut stream,
+        )
+        .unwrap();
+        assert_eq!("", std::str::from_utf8(stream.get_ref()).unwrap());
+
+        let mut stream = Cursor::new(Vec::new());
+        filter(
+            &db_paths,
+            Path::new("tests/data/random_3_2_low_quality.fq"),
+            &FilterOptions {
+                max_divergence: 0,
+                inverse: false,
+                column_weights: ColumnWeights::new(vec![2], None),
+            },
+            &mut stream,
+        )
+        .unwrap();
+        assert_eq!(
+            "@q1\nCTG\n+\nII#\n@q2\nAGT\n+\n##I\n",
+            std::str::from_utf8(stream.get_ref()).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_filter_inverse() {
+        let mut stream = Cursor::new(Vec::new());
+        filter(
+            &[PathBuf::from("tests/data/random_3_2.fna.smafadb")],
+            Path::new("tests/data/degenerate.fna"),
+            &FilterOptions {
+                max_divergence: 99,
+                inverse: true,
+                ..Default::default()
+            },
+            &mut stream,
+        )
+        .unwrap();
+        // None of the sequences are the same length as the database windows.
+        assert_eq!(
+            ">1\nCTTRGG\n>2\nAGGUGA\n>3\nYACTTT\n",
+            std::str::from_utf8(stream.get_ref()).unwrap()
+        );
+    }
+}
@@ -1,25 +1,56 @@
 use needletail::parse_fastx_file;
 use serde::{Deserialize, Serialize};
 
-use std::io::{Read, Write};
+use std::collections::HashSet;
+use std::io::{BufWriter, Read, Write};
 use std::num::{NonZeroU8, NonZeroUsize};
 use std::path::{Path, PathBuf};
 use std::time::Instant;
 use std::{error::Error, fs::File};
 
-use log::{debug, info};
+use log::{debug, info, warn};
 
+mod chimera;
+pub use chimera::{chimera, ChimeraOptions};
 mod cluster;
-pub use cluster::cluster;
+pub use cluster::{cluster, ClusterOptions};
+mod denoise;
+pub use denoise::{denoise, DenoiseOptions};
+mod derep;
+pub use derep::derep;
+mod filter;
+pub use filter::{filter, FilterOptions};
 
 pub const AUTHOR_AND_EMAIL: &str =
     "Ben J. Woodcroft, Centre for Microbiome Research, School of Biomedical Sciences, Faculty of Health, Queensland University of Technology <benjwoodcroft near gmail.com>";
 
 pub const CURRENT_DB_VERSION: u32 = 2;
+/// Versions of databases holding several markers start here, and versions of
+/// single window set databases must stay below it, so that the version alone
+/// tells the two formats apart.
+pub const MULTI_MARKER_DB_VERSION_BASE: u32 = 1 << 16;
+pub const CURRENT_MULTI_MARKER_DB_VERSION: u32 = MULTI_MARKER_DB_VERSION_BASE + 1;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 struct SeqEncoding(Vec<u64>);
 
+impl SeqEncoding {
+    #[inline(always)]
+    fn distance(&self, other: &SeqEncoding, weight_masks: Option<&WeightMasks>) -> usize {
+        match weight_masks {
+            Some(weight_masks) => weight_masks.distance(self, other),
+            None => {
+                self.0
+                    .iter()
+                    .zip(other.0.iter())
+                    .map(|(a, b)| (a ^ b).count_ones() as usize)
+                    .sum::<usize>()
+                    / 2
+            }
+        }
+    }
+}
+
 #[derive(Clone)]
 struct SeqEncodingLength {
     encoding: SeqEncoding,
@@ -51,6 +82,211 @@ impl SeqEncodingLength {
     }
 }
 
+/// Columns to ignore and per-column integer weights to use when calculating
+/// distances. By default every column has weight 1.
+#[derive(Debug, Clone, Default)]
+pub struct ColumnWeights {
+    // 0-based positions of columns that are ignored
+    masked_columns: Vec<usize>,
+    // One weight per column, if given
+    weights: Option<Vec<u32>>,
+}
+
+impl ColumnWeights {
+    pub fn new(masked_columns: Vec<usize>, weights: Option<Vec<u32>>) -> Self {
+        ColumnWeights {
+            masked_columns,
+            weights,
+        }
+    }
+
+    /// Check that every masked column is within sequences of the given
+    /// length, which should be the longest the weights are applied to.
+    fn check_masked_columns(&self, max_len: usize) -> Result<(), Box<dyn Error>> {
+        match self.masked_columns.iter().max() {
+            Some(&column) if column >= max_len => Err(format!(
+                "Cannot mask column {} since the sequences have length at most {}",
+                column + 1,
+                max_len
+            )
+            .into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Weight of each column for sequences of the given length, with masked
+    /// columns having weight 0. Masked columns beyond the end of the sequences
+    /// are ignored, so that the same mask can be applied to sequences of
+    /// different lengths.
+    fn column_weights(&self, len: usize) -> Result<Vec<u32>, Box<dyn Error>> {
+        let mut weights = match &self.weights {
+            Some(weights) => {
+                if weights.len() != len {
+                    return Err(format!(
+                        "{} column weights were given, but the sequences have length {}",
+                        weights.len(),
+                        len
+                    )
+                    .into());
+                }
+                weights.clone()
+            }
+            None => vec![1; len],
+        };
+        for &column in self.masked_columns.iter().filter(|c| **c < len) {
+            weights[column] = 0;
+        }
+        Ok(weights)
+    }
+
+    /// Convert to bitmasks over the encoding of sequences of the given length.
+    /// Returns None if all columns have weight 1, so that the faster
+    /// unweighted distance calculation can be used.
+    fn to_masks(&self, len: usize) -> Result<Option<WeightMasks>, Box<dyn Error>> {
+        let weights = self.column_weights(len)?;
+        if weights.iter().all(|&w| w == 1) {
+            return Ok(None);
+        }
+        Ok(Some(WeightMasks::from_weights(
+            weights.iter().map(|&w| w as usize),
+            len,
+        )))
+    }
+}
+
+/// Parse 1-based column positions and inclusive ranges e.g. "1-3,10" into
+/// 0-based column positions.
+pub fn parse_column_ranges(ranges: &str) -> Result<Vec<usize>, Box<dyn Error>> {
+    let parse_position = |p: &str| -> Result<usize, Box<dyn Error>> {
+        match p.trim().parse::<usize>() {
+            Ok(0) | Err(_) => Err(format!(
+                "Could not parse \"{}\" as a column position counting from 1",
+                p
+            )
+            .into()),
+            Ok(position) => Ok(position - 1),
+        }
+    };
+    let mut columns = Vec::new();
+    for range in ranges.split(',').filter(|r| !r.trim().is_empty()) {
+        match range.split_once('-') {
+            Some((first, last)) => {
+                let (first, last) = (parse_position(first)?, parse_position(last)?);
+                if last < first {
+                    return Err(format!("Column range \"{}\" is backwards", range).into());
+                }
+                columns.extend(first..=last);
+            }
+            None => columns.push(parse_position(range)?),
+        }
+    }
+    Ok(columns)
+}
+
+/// Read per-column integer weights separated by whitespace or commas.
+pub fn read_column_weights(path: &Path) -> Result<Vec<u32>, Box<dyn Error>> {
+    let mut contents = String::new();
+    File::open(path)?.read_to_string(&mut contents)?;
+    contents
+        .split(|c: char| c.is_whitespace() || c == ',')
+        .filter(|w| !w.is_empty())
+        .map(|w| {
+            w.parse::<u32>().map_err(|_| {
+                format!("Could not parse column weight \"{}\" as an integer", w).into()
+            })
+        })
+        .collect()
+}
+
+/// How base quality scores of FASTQ queries are used to calculate a
+/// quality-adjusted divergence.
+#[derive(Debug, Clone, Copy)]
+pub enum QualityScoring {
+    /// Ignore bases with a phred quality score below this threshold.
+    MinQuality(u8),
+    /// Weight each mismatch by the probability that the query base is correct.
+    ErrorProbability,
+}
+
+impl QualityScoring {
+    /// Quality-adjusted divergences are integers, this many times the
+    /// divergence they represent.
+    fn scale(&self) -> usize {
+        match self {
+            QualityScoring::MinQuality(_) => 1,
+            QualityScoring::ErrorProbability => 100,
+        }
+    }
+
+    /// Weight of a column given the quality of the query base, in units of
+    /// 1/scale().
+    fn column_factor(&self, quality: u8) -> usize {
+        let phred = quality.saturating_sub(33);
+        match self {
+            QualityScoring::MinQuality(min_quality) => (phred >= *min_quality) as usize,
+            QualityScoring::ErrorProbability => {
+                let error_probability = 10f64.powf(-(phred as f64) / 10.0);
+                (100.0 * (1.0 - error_probability)).round() as usize
+            }
+        }
+    }
+
+    fn format(&self, adjusted_distance: usize) -> String {
+        match self {
+            QualityScoring::MinQuality(_) => adjusted_distance.to_string(),
+            QualityScoring::ErrorProbability => {
+                format!("{:.2}", adjusted_distance as f64 / self.scale() as f64)
+            }
+        }
+    }
+}
+
+/// Column weights as bitmasks over the encoding of a sequence.
+#[derive(Debug, Clone)]
+struct WeightMasks {
+    // (weight, mask) for each distinct non-zero weight
+    groups: Vec<(usize, Vec<u64>)>,
+}
+
+impl WeightMasks {
+    fn from_weights(weights: impl Iterator<Item = usize>, len: usize) -> Self {
+        // One mask for each distinct weight, with all 5 bits set for each
+        // column with that weight.
+        let mut groups: Vec<(usize, Vec<u64>)> = Vec::new();
+        let num_chunks = len.div_ceil(12);
+        for (column, weight) in weights.enumerate() {
+            if weight == 0 {
+                continue;
+            }
+            let group = match groups.iter().position(|(w, _)| *w == weight) {
+                Some(i) => i,
+                None => {
+                    groups.push((weight, vec![0; num_chunks]));
+                    groups.len() - 1
+                }
+            };
+            groups[group].1[column / 12] |= 0b11111 << (5 * (column % 12));
+        }
+        WeightMasks { groups }
+    }
+
+    fn distance(&self, a: &SeqEncoding, b: &SeqEncoding) -> usize {
+        self.groups
+            .iter()
+            .map(|(weight, mask)| {
+                weight
+                    * (a.0
+                        .iter()
+                        .zip(b.0.iter())
+                        .zip(mask.iter())
+                        .map(|((a, b), m)| ((a ^ b) & m).count_ones() as usize)
+                        .sum::<usize>()
+                        / 2)
+            })
+            .sum()
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 struct WindowSet {
     version: u32,
@@ -68,7 +304,12 @@ impl WindowSet {
         }
     }
 
-    fn get_distances(&self, seq: &SeqEncodingLength, distances: &mut [usize]) {
+    fn get_distances(
+        &self,
+        seq: &SeqEncodingLength,
+        weight_masks: Option<&WeightMasks>,
+        distances: &mut [usize],
+    ) {
         if let Some(n) = self.len {
             if n.get() != seq.len {
                 panic!(
@@ -78,13 +319,7 @@ impl WindowSet {
             }
         }
         for (window, distance) in self.windows.iter().zip(distances.iter_mut()) {
-            *distance = window
-                .0
-                .iter()
-                .zip(seq.encoding.0.iter())
-                .map(|(a, b)| (a ^ b).count_ones() as usize)
-                .sum::<usize>()
-                / 2
+            *distance = window.distance(&seq.encoding, weight_masks);
         }
     }
 
@@ -134,7 +369,34 @@ impl WindowSet {
     }
 }
 
-pub fn makedb(subject_fasta: &Path, db_path: &Path) -> Result<(), Box<dyn Error>> {
+/// A window set inside a multi-marker database. Subjects of each marker are
+/// grouped by length, with one window set for each length.
+#[derive(Serialize, Deserialize, Debug)]
+struct Marker {
+    // None when the database was made from a single subject file
+    name: Option<String>,
+    windows: WindowSet,
+    // Number of each window in the subject file, counting from 0
+    subject_numbers: Vec<usize>,
+}
+
+/// Database holding a window set for each of several markers, each of which
+/// can have its own window length.
+#[derive(Serialize, Deserialize, Debug)]
+struct MultiMarkerDb {
+    version: u32,
+    markers: Vec<Marker>,
+}
+
+/// Subjects of one length, and the number of each in the subject file.
+struct LengthBucket {
+    windows: WindowSet,
+    subject_numbers: Vec<usize>,
+}
+
+/// Encode subject sequences, grouping them by length in the order that each
+/// length is first seen.
+fn encode_subjects(subject_fasta: &Path) -> Vec<LengthBucket> {
     // Iterate over lines, creating a vector of u8, where the lowest 5 bits of the u8
     // are the input nucleotides, one-hot encoded
 
@@ -144,24 +406,128 @@ pub fn makedb(subject_fasta: &Path, db_path: &Path) -> Result<(), Box<dyn Error>
         parse_fastx_file(subject_fasta).expect("valid path/file of subject fasta");
 
     info!("Encoding subject sequences ..");
-    let mut windows = WindowSet::new(CURRENT_DB_VERSION);
+    let mut buckets: Vec<LengthBucket> = Vec::new();
+    let mut subject_number = 0;
     while let Some(record) = subject_reader.next() {
         let record = record.expect("valid record");
         let encoded = SeqEncodingLength::from_bytes(record.id(), &record.seq());
-        windows.push_encoding(encoded);
+        let bucket = match buckets
+            .iter()
+            .position(|b| b.windows.len.map(NonZeroUsize::get) == Some(encoded.len))
+        {
+            Some(i) => &mut buckets[i],
+            None => {
+                buckets.push(LengthBucket {
+                    windows: WindowSet::new(CURRENT_DB_VERSION),
+                    subject_numbers: vec![],
+                });
+                buckets.last_mut().unwrap()
+            }
+        };
+        bucket.windows.push_encoding(encoded);
+        bucket.subject_numbers.push(subject_number);
+        subject_number += 1;
     }
+    buckets
+}
+
+fn write_db<T: Serialize>(db: &T, db_path: &Path) -> Result<(), Box<dyn Error>> {
+    let mut ferris_file = File::create(db_path)?;
+    ferris_file.write_all(&postcard::to_allocvec(db).unwrap())?;
+    info!("DB file written");
+    Ok(())
+}
+
+pub fn makedb(subject_fasta: &Path, db_path: &Path) -> Result<(), Box<dyn Error>> {
+    let mut buckets = encode_subjects(subject_fasta);
 
     info!(
         "Encoding of {} sequences complete, writing db file {}",
-        windows.windows.len(),
+        buckets
+            .iter()
+            .map(|b| b.windows.windows.len())
+            .sum::<usize>(),
         db_path.to_string_lossy()
     );
 
-    // Encode
-    let mut ferris_file = File::create(db_path)?;
-    ferris_file.write_all(&postcard::to_allocvec(&windows).unwrap())?;
-    info!("DB file written");
-    Ok(())
+    // Encode. Subjects all of one length are written as a plain window set,
+    // otherwise there is one window set per length.
+    if buckets.len() <= 1 {
+        let windows = buckets
+            .pop()
+            .map_or_else(|| WindowSet::new(CURRENT_DB_VERSION), |b| b.windows);
+        return write_db(&windows, db_path);
+    }
+    info!(
+        "Subject sequences have {} different lengths, so writing one window set per length",
+        buckets.len()
+    );
+    write_db(
+        &MultiMarkerDb {
+            version: CURRENT_MULTI_MARKER_DB_VERSION,
+            markers: buckets
+                .into_iter()
+                .map(|bucket| Marker {
+                    name: None,
+                    windows: bucket.windows,
+                    subject_numbers: bucket.subject_numbers,
+                })
+                .collect(),
+        },
+        db_path,
+    )
+}
+
+/// Make a multi-marker database from a manifest file. Each line of the
+/// manifest is a marker name and the path to its subject sequences, separated
+/// by a tab.
+pub fn makedb_multi_marker(manifest: &Path, db_path: &Path) -> Result<(), Box<dyn Error>> {
+    let mut contents = String::new();
+    File::open(manifest)?.read_to_string(&mut contents)?;
+
+    let mut names = HashSet::new();
+    let mut markers: Vec<Marker> = Vec::new();
+    for line in contents.lines() {
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (name, subject_fasta) = line.split_once('\t').ok_or_else(|| {
+            format!(
+                "Manifest line \"{}\" is not a marker name and path separated by a tab",
+                line
+            )
+        })?;
+        if !names.insert(name) {
+            return Err(format!(
+                "Marker \"{}\" is given more than once in the manifest",
+                name
+            )
+            .into());
+        }
+        info!("Encoding marker {}", name);
+        markers.extend(
+            encode_subjects(Path::new(subject_fasta.trim()))
+                .into_iter()
+                .map(|bucket| Marker {
+                    name: Some(name.to_string()),
+                    windows: bucket.windows,
+                    subject_numbers: bucket.subject_numbers,
+                }),
+        );
+    }
+
+    info!(
+        "Encoding of {} markers complete, writing db file {}",
+        names.len(),
+        db_path.to_string_lossy()
+    );
+    write_db(
+        &MultiMarkerDb {
+            version: CURRENT_MULTI_MARKER_DB_VERSION,
+            markers,
+        },
+        db_path,
+    )
 }
 
 const fn create_lut() -> [u8; 256] {
@@ -195,127 +561,492 @@ fn encode_single(c: u8) -> Option<NonZeroU8> {
     NonZeroU8::new(encoding)
 }
 
-pub fn query(
-    db_path: &Path,
-    query_fasta: &Path,
-    max_divergence: Option<u32>,
-    max_num_hits: Option<u32>,
-    limit_per_sequence: Option<u32>,
-) -> Result<(), Box<dyn Error>> {
-    // Decode
+/// A window set loaded for searching, with the name reported in output. The
+/// name is the marker name for multi-marker databases, otherwise the path of
+/// the database.
+struct SearchTarget {
+    name: String,
+    windows: WindowSet,
+    // Number of each window in the subject file, if they are not numbered
+    // in order
+    subject_numbers: Option<Vec<usize>>,
+}
+
+fn load_targets(db_path: &Path) -> Result<Vec<SearchTarget>, Box<dyn Error>> {
     info!("Decoding db file {:?}", db_path);
-    let start = Instant::now();
     let mut ferris_file = File::open(db_path)?;
     let mut buffer = Vec::new();
     ferris_file.read_to_end(&mut buffer)?;
 
     // Check that the version of the db file is the most recent. We do not
-    // support backwards compatibility.
-    let version: u32 = postcard::from_bytes(&buffer[0..4])?;
-    if version != CURRENT_DB_VERSION {
-        panic!("Unsupported db file version: {}. This version of smafa only works with version {} databases. The last version to support version 1 databases was v0.7.1.", version, CURRENT_DB_VERSION);
+    // support backwards compatibility. The version is read from the whole
+    // buffer, since a database without windows may be shorter than 4 bytes.
+    let version: u32 = postcard::from_bytes(&buffer)?;
+    let targets: Vec<SearchTarget> = match version {
+        CURRENT_DB_VERSION => vec![SearchTarget {
+            name: db_path.to_string_lossy().into_owned(),
+            windows: postcard::from_bytes(&buffer)?,
+            subject_numbers: None,
+        }],
+        CURRENT_MULTI_MARKER_DB_VERSION => {
+            let db: MultiMarkerDb = postcard::from_bytes(&buffer)?;
+            db.markers
+                .into_iter()
+                .map(|marker| SearchTarget {
+                    name: marker
+                        .name
+                        .unwrap_or_else(|| db_path.to_string_lossy().into_owned()),
+                    windows: marker.windows,
+                    subject_numbers: Some(marker.subject_numbers),
+                })
+                .collect()
+        }
+        _ if version >= MULTI_MARKER_DB_VERSION_BASE => panic!("Unsupported multi-marker db file version: {}. This version of smafa only works with version {} multi-marker databases.", version, CURRENT_MULTI_MARKER_DB_VERSION),
+        _ => panic!("Unsupported db file version: {}. This version of smafa only works with version {} databases, or version {} multi-marker databases. The last version to support version 1 databases was v0.7.1.", version, CURRENT_DB_VERSION, CURRENT_MULTI_MARKER_DB_VERSION),
+    };
+    // Window sets without windows have nothing to report hits from.
+    Ok(targets
+        .into_iter()
+        .filter(|target| !target.windows.windows.is_empty())
+        .collect())
+}
+
+/// Window sets to search, and the masks to use with each.
+type SearchTargets = (Vec<SearchTarget>, Vec<Option<WeightMasks>>);
+
+/// Load the window sets of each database to search, and the masks to use with
+/// each given the column weights.
+fn load_search_targets(
+    db_paths: &[PathBuf],
+    column_weights: &ColumnWeights,
+) -> Result<SearchTargets, Box<dyn Error>> {
+    if db_paths.is_empty() {
+        return Err("No databases were given to search".into());
+    }
+    let mut targets = Vec::with_capacity(db_paths.len());
+    for db_path in db_paths {
+        targets.extend(load_targets(db_path)?);
+    }
+    // Masks are clipped to the length of each database, so only columns
+    // beyond the longest are an error.
+    if let Some(max_len) = targets
+        .iter()
+        .filter_map(|target| target.windows.len)
+        .map(NonZeroUsize::get)
+        .max()
+    {
+        column_weights.check_masked_columns(max_len)?;
+    }
+    let weight_masks = targets
+        .iter()
+        .map(|target| match target.windows.len {
+            Some(len) => column_weights.to_masks(len.get()),
+            None => Ok(None),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok((targets, weight_masks))
+}
+
+/// Read a list of database paths, one per line. Blank lines and lines
+/// starting with '#' are ignored.
+pub fn read_database_list(list_path: &Path) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    let mut contents = String::new();
+    File::open(list_path)?.read_to_string(&mut contents)?;
+    Ok(contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(PathBuf::from)
+        .collect())
+}
+
+/// Offsets of a query at which windows are compared when sliding windows
+/// across queries longer than the windows.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct OffsetRange {
+    /// First offset, counting from 0.
+    pub min: usize,
+    /// Last offset. If None, the last offset where the window fits.
+    pub max: Option<usize>,
+}
+
+impl OffsetRange {
+    fn offsets(&self, query_len: usize, window_len: usize) -> std::ops::RangeInclusive<usize> {
+        match query_len.checked_sub(window_len) {
+            Some(last_offset) => {
+                self.min..=self.max.map_or(last_offset, |max| max.min(last_offset))
+            }
+            // Empty range if the window is longer than the query
+            #[allow(clippy::reversed_empty_ranges)]
+            None => 1..=0,
+        }
     }
-    let windows: WindowSet = postcard::from_bytes(&buffer)?;
+}
+
+/// Precomputed weights for calculating distances to one window set.
+struct TargetScorer {
+    weight_masks: Option<WeightMasks>,
+    // Weight of each column, used in combination with quality scores
+    base_column_weights: Vec<u32>,
+    quality_scoring: Option<QualityScoring>,
+}
+
+impl TargetScorer {
+    /// Calculate the distances between the query and each window, and if
+    /// quality scoring is used, the quality-adjusted distances.
+    fn calculate_distances(
+        &self,
+        windows: &WindowSet,
+        query_vec: &SeqEncodingLength,
+        qualities: Option<&[u8]>,
+        distances: &mut [usize],
+        adjusted_distances: &mut [usize],
+    ) {
+        // Get the minimum distance between the query and each window using xor.
+        windows.get_distances(query_vec, self.weight_masks.as_ref(), distances);
+
+        // Recalculate distances with columns also weighted by the quality
+        // of each query base.
+        if let Some(quality_scoring) = self.quality_scoring {
+            let quality_masks = match qualities {
+                Some(qualities) => WeightMasks::from_weights(
+                    self.base_column_weights
+                        .iter()
+                        .zip(qualities.iter())
+                        .map(|(&w, &q)| w as usize * quality_scoring.column_factor(q)),
+                    query_vec.len,
+                ),
+                // FASTA queries are treated as having perfect quality.
+                None => WeightMasks::from_weights(
+                    self.base_column_weights
+                        .iter()
+                        .map(|&w| w as usize * quality_scoring.scale()),
+                    query_vec.len,
+                ),
+            };
+            windows.get_distances(query_vec, Some(&quality_masks), adjusted_distances);
+        }
+    }
+}
+
+/// How each query is identified in the first column(s) of query output.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum QueryLabel {
+    /// The number of the query in the query file, counting from 0.
+    #[default]
+    Number,
+    /// The identifier of the query, i.e. the header up to the first whitespace.
+    Id,
+    /// The number followed by the identifier.
+    Both,
+}
+
+/// Identifier of a sequence, i.e. the header up to the first whitespace.
+fn sequence_identifier(header: &[u8]) -> String {
+    let id = header
+        .split(|b| b.is_ascii_whitespace())
+        .next()
+        .unwrap_or_default();
+    String::from_utf8_lossy(id).into_owned()
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct QueryOptions {
+    pub max_divergence: Option<u32>,
+    pub max_num_hits: Option<u32>,
+    pub limit_per_sequence: Option<u32>,
+    pub marker_header_field: Option<NonZeroUsize>,
+    pub column_weights: ColumnWeights,
+    pub quality_scoring: Option<QualityScoring>,
+    /// Report the second best distance and the number of distinct subject
+    /// sequences at the best distance.
+    pub confidence_columns: bool,
+    /// Write queries without any hits to this file.
+    pub unmatched_output: Option<PathBuf>,
+    /// Write queries whose best hit is further than novel_threshold to
+    /// novel_output.
+    pub novel_threshold: Option<u32>,
+    pub novel_output: Option<PathBuf>,
+    pub query_label: QueryLabel,
+    /// Report which database each hit came from, even when only one database
+    /// is searched.
+    pub database_column: bool,
+    /// Compare windows at each offset of queries longer than them.
+    pub sliding: Option<OffsetRange>,
+}
+
+pub fn query(
+    db_paths: &[PathBuf],
+    query_fasta: &Path,
+    options: &QueryOptions,
+    print_stream: &mut dyn Write,
+) -> Result<(), Box<dyn Error>> {
+    // Decode
+    let start = Instant::now();
+    let (targets, weight_masks) = load_search_targets(db_paths, &options.column_weights)?;
+    let scorers = targets
+        .iter()
+        .zip(weight_masks)
+        .map(|(target, weight_masks)| match target.windows.len {
+            Some(len) => Ok(TargetScorer {
+                weight_masks,
+                // Quality scores are combined with the column weights for each
+                // query.
+                base_column_weights: match options.quality_scoring {
+                    Some(_) => options.column_weights.column_weights(len.get())?,
+                    None => vec![],
+                },
+                quality_scoring: options.quality_scoring,
+            }),
+            None => Ok(TargetScorer {
+                weight_masks: None,
+                base_column_weights: vec![],
+                quality_scoring: None,
+            }),
+        })
+        .collect::<Result<Vec<_>, Box<dyn Error>>>()?;
+    // Report which database a hit came from whenever several could be
+    // searched, so that the columns do not depend on how many are given.
+    let print_database = options.database_column
+        || db_paths.len() > 1
+        || targets
+            .iter()
+            .map(|target| &target.name)
+            .collect::<HashSet<_>>()
+            .len()
+            > 1;
 
     // Open the query file as a fasta file.
     let mut query_reader = parse_fastx_file(query_fasta).expect("valid path/file of query fasta");
 
-    // 1 is a special case, it is equivalent to None.
-    let max_divergence_for_match = max_num_hits.filter(|&max_num_hits| max_num_hits != 1);
+    let scale = options.quality_scoring.map_or(1, |q| q.scale());
+    let mut unmatched_writer = options
+        .unmatched_output
+        .as_ref()
+        .map(|path| File::create(path).map(BufWriter::new))
+        .transpose()?;
+    let mut novel_writer = match (options.novel_threshold, &options.novel_output) {
+        (Some(threshold), Some(path)) => Some((
+            threshold as usize * scale,
+            BufWriter::new(File::create(path)?),
+        )),
+        (None, None) => None,
+        _ => {
+            return Err("A novel threshold and novel output must be specified together".into());
+        }
+    };
 
-    // Pre-initialise the distances vector so don't have to continually reallocate.
-    let mut distances = vec![0; windows.windows.len()];
+    // Pre-initialise the distances vectors so don't have to continually reallocate.
+    let mut distances = targets
+        .iter()
+        .map(|target| vec![0; target.windows.windows.len()])
+        .collect::<Vec<_>>();
+    let mut adjusted_distances = distances.clone();
+    // When sliding, the distances at each offset are calculated here before
+    // being compared to the best so far.
+    let mut offsets = match options.sliding {
+        Some(_) => distances.clone(),
+        None => vec![],
+    };
+    let mut offset_distances = offsets.clone();
+    let mut offset_adjusted_distances = offsets.clone();
 
     // Iterate over the query file.
     info!("Querying ..");
-    let mut query_number: u32 = 0;
+    let mut query_number: u64 = 0;
     while let Some(record) = query_reader.next() {
         // encode a line from stdin as a vector of bools
         let record = record.expect("Failed to parse query sequence");
         let query_vec = SeqEncodingLength::from_bytes(record.id(), &record.seq());
+        let query_id = sequence_identifier(record.id());
 
-        // Get the minimum distance between the query and each window using xor.
-        windows.get_distances(&query_vec, &mut distances);
-
-        // Find the max_num_hits'th minimum distance.
-        match max_divergence_for_match {
-            Some(max_num_hits) => {
-                let mut min_distances = distances
-                    .iter()
-                    .enumerate()
-                    .map(|(i, d)| (*d, i))
-                    .collect::<Vec<_>>();
-                // There might be a faster way of doing this using a priority
-                // queue, but eh for now unless it really is slow.
-                min_distances.sort();
-
-                // If max num hits is greater than the number of windows, just print them all.
-                let max_distance = match max_num_hits > min_distances.len() as u32 {
-                    true => *distances.iter().max().unwrap(),
-                    false => min_distances[(max_num_hits - 1) as usize].0,
-                };
-
-                // Print out the windows that qualify in order of increasing distance.
-                let mut last_sequence: Option<(String, u32)> = None;
-                let mut new_last_sequence: Option<(String, u32)>; // to get around borrow checker
-                for (distance, i) in min_distances.iter() {
-                    if *distance <= max_distance
-                        && (max_divergence.is_none()
-                            || *distance <= max_divergence.unwrap() as usize)
-                    {
-                        let s = windows.get_as_string(*i);
-                        debug!("Found hit sequence {} at distance {}", s, distance);
-
-                        if let Some(limit_per_sequence_unwrapped) = limit_per_sequence {
-                            // limit per sequence
-                            match &last_sequence {
-                                Some((last_seq, last_seq_count)) => {
-                                    if last_seq == &s {
-                                        if last_seq_count >= &limit_per_sequence_unwrapped {
-                                            continue;
-                                        } else {
-                                            new_last_sequence =
-                                                Some((s.clone(), last_seq_count + 1));
-                                        }
-                                    } else {
-                                        new_last_sequence = Some((s.clone(), 1));
-                                    }
-                                }
-                                None => {
-                                    new_last_sequence = Some((s.clone(), 1));
-                                }
-                            }
-                            last_sequence = new_last_sequence;
+        // If requested, the marker to search is named in the query header.
+        let marker = match options.marker_header_field {
+            Some(field) => {
+                match record
+                    .id()
+                    .split(|b| b.is_ascii_whitespace())
+                    .filter(|f| !f.is_empty())
+                    .nth(field.get() - 1)
+                {
+                    Some(marker) => Some(marker),
+                    None => {
+                        warn!(
+                            "Query {} ({}) has no header field {} naming its marker, so it was not searched",
+                            query_number,
+                            String::from_utf8_lossy(record.id()),
+                            field
+                        );
+                        if let Some(unmatched_writer) = unmatched_writer.as_mut() {
+                            record.write(unmatched_writer, None)?;
                         }
-
-                        // Print the window if we make it here.
-                        println!("{}\t{}\t{}\t{}", query_number, i, distance, s);
+                        query_number += 1;
+                        continue;
                     }
                 }
             }
-            None => {
-                // Find the minimum distance.
-                let min_distance = distances.iter().min().unwrap();
-                debug!("Min distance: {}", min_distance);
+            None => None,
+        };
 
-                if limit_per_sequence.is_some() {
-                    panic!("limit_per_sequence is implemented unless max_num_hits > 1. It can be implemented by analogy, just haven't gotten around to it.");
+        // Each query is only searched against databases with windows of the
+        // same length, or when sliding, no longer than the query.
+        let mut num_searched = 0;
+        let mut any_hits = false;
+        let mut best_score: Option<usize> = None;
+        for (target_index, (target, target_distances)) in
+            targets.iter().zip(distances.iter_mut()).enumerate()
+        {
+            let window_len = match target.windows.len {
+                Some(len) => len.get(),
+                None => continue,
+            };
+            if marker.is_some_and(|m| m != target.name.as_bytes()) {
+                continue;
+            }
+            let target_adjusted_distances = &mut adjusted_distances[target_index];
+            let target_offsets = match options.sliding {
+                None => {
+                    if window_len != query_vec.len {
+                        continue;
+                    }
+                    scorers[target_index].calculate_distances(
+                        &target.windows,
+                        &query_vec,
+                        record.qual(),
+                        target_distances,
+                        target_adjusted_distances,
+                    );
+                    None
                 }
-
-                // Print the windows with the minimum distance.
-                if max_divergence.is_none() || *min_distance <= max_divergence.unwrap() as usize {
-                    for (i, distance) in distances.iter().enumerate() {
-                        if distance == min_distance {
-                            let s = windows.get_as_string(i);
-                            println!("{}\t{}\t{}\t{}", query_number, i, distance, s);
+                Some(offset_range) => {
+                    let seq = record.seq();
+                    let offsets_to_search = offset_range.offsets(seq.len(), window_len);
+                    if offsets_to_search.is_empty() {
+                        continue;
+                    }
+                    let target_offsets = &mut offsets[target_index];
+                    for offset in offsets_to_search {
+                        let window_vec = SeqEncodingLength::from_bytes(
+                            record.id(),
+                            &seq[offset..offset + window_len],
+                        );
+                        let (distances_here, adjusted_distances_here) =
+                            if offset == offset_range.min {
+                                // The first offset is recorded directly as the best
+                                (&mut *target_distances, &mut *target_adjusted_distances)
+                            } else {
+                                (
+                                    &mut offset_distances[target_index],
+                                    &mut offset_adjusted_distances[target_index],
+                                )
+                            };
+                        scorers[target_index].calculate_distances(
+                            &target.windows,
+                            &window_vec,
+                            record.qual().map(|qual| &qual[offset..offset + window_len]),
+                            distances_here,
+                            adjusted_distances_here,
+                        );
+                        if offset == offset_range.min {
+                            target_offsets.fill(offset);
+                            continue;
+                        }
+                        // Keep the offset with the best score for each window,
+                        // the first if there is a tie.
+                        let scores_here = match options.quality_scoring {
+                            Some(_) => &offset_adjusted_distances[target_index],
+                            None => &offset_distances[target_index],
+                        };
+                        for i in 0..target_offsets.len() {
+                            let best = match options.quality_scoring {
+                                Some(_) => target_adjusted_distances[i],
+                                None => target_distances[i],
+                            };
+                            if scores_here[i] < best {
+                                target_distances[i] = offset_distances[target_index][i];
+                                target_adjusted_distances[i] =
+                                    offset_adjusted_distances[target_index][i];
+                                target_offsets[i] = offset;
+                            }
                         }
                     }
+                    Some(target_offsets.as_slice())
                 }
+            };
+            num_searched += 1;
+            let target_adjusted_distances = options
+                .quality_scoring
+                .map(|_| target_adjusted_distances.as_slice());
+
+            let hit_printer = HitPrinter {
+                query_number,
+                query_id: &query_id,
+                query_label: options.query_label,
+                database: print_database.then_some(target.name.as_str()),
+                quality_scoring: options.quality_scoring,
+                confidence: options.confidence_columns.then(|| {
+                    Confidence::new(
+                        &target.windows,
+                        target_adjusted_distances.unwrap_or(target_distances),
+                    )
+                }),
+                offsets: target_offsets,
+                subject_numbers: target.subject_numbers.as_deref(),
+            };
+            let target_best_score = target_adjusted_distances
+                .unwrap_or(target_distances)
+                .iter()
+                .min()
+                .copied();
+            best_score = best_score.min(target_best_score).or(target_best_score);
+            any_hits |= report_hits(
+                &target.windows,
+                target_distances,
+                target_adjusted_distances,
+                options,
+                &hit_printer,
+                print_stream,
+            )?;
+        }
+        if num_searched == 0 {
+            warn!(
+                "No database{} has windows of {} {}, so query {} ({}) was not searched",
+                match marker {
+                    Some(m) => format!(" for marker {}", String::from_utf8_lossy(m)),
+                    None => String::new(),
+                },
+                match options.sliding {
+                    Some(_) => "a length that can be slid across length",
+                    None => "length",
+                },
+                query_vec.len,
+                query_number,
+                String::from_utf8_lossy(record.id())
+            );
+        }
+
+        if !any_hits {
+            if let Some(unmatched_writer) = unmatched_writer.as_mut() {
+                record.write(unmatched_writer, None)?;
+            }
+        }
+        if let (Some((novel_threshold, novel_writer)), Some(best_score)) =
+            (novel_writer.as_mut(), best_score)
+        {
+            if best_score > *novel_threshold {
+                record.write(novel_writer, None)?;
             }
         }
 
         query_number += 1;
     }
+    print_stream.flush()?;
+    if let Some(writer) = unmatched_writer.as_mut() {
+        writer.flush()?;
+    }
+    if let Some((_, writer)) = novel_writer.as_mut() {
+        writer.flush()?;
+    }
 
     info!(
         "Querying complete, took {} seconds",
@@ -324,6 +1055,219 @@ pub fn query(
     Ok(())
 }
 
+/// How clearly a query is assigned to the subject sequence(s) at its best
+/// distance.
+struct Confidence {
+    // Best distance to a subject with a different sequence to the best hit,
+    // None if there is no such subject
+    second_best_distance: Option<usize>,
+    num_distinct_best_sequences: usize,
+}
+
+impl Confidence {
+    fn new(windows: &WindowSet, distances: &[usize]) -> Self {
+        let best_distance = *distances.iter().min().unwrap();
+        let best_sequences = windows
+            .windows
+            .iter()
+            .zip(distances.iter())
+            .filter(|(_, d)| **d == best_distance)
+            .map(|(w, _)| &w.0)
+            .collect::<HashSet<_>>();
+        // Identical sequences are the same distance from the query, so the
+        // second best distinct hit is only at the best distance if there are
+        // several distinct best sequences.
+        let second_best_distance = match best_sequences.len() {
+            1 => distances
+                .iter()
+                .filter(|d| **d > best_distance)
+                .min()
+                .copied(),
+            _ => Some(best_distance),
+        };
+        Confidence {
+            second_best_distance,
+            num_distinct_best_sequences: best_sequences.len(),
+        }
+    }
+}
+
+/// Fields shared by each line of output reported for one query against one
+/// database.
+struct HitPrinter<'a> {
+    query_number: u64,
+    query_id: &'a str,
+    query_label: QueryLabel,
+    database: Option<&'a str>,
+    quality_scoring: Option<QualityScoring>,
+    confidence: Option<Confidence>,
+    // Best offset of each window when sliding
+    offsets: Option<&'a [usize]>,
+    subject_numbers: Option<&'a [usize]>,
+}
+
+impl HitPrinter<'_> {
+    fn print(
+        &self,
+        print_stream: &mut dyn Write,
+        window_number: usize,
+        distance: usize,
+        adjusted_distance: Option<usize>,
+        subject: &str,
+    ) -> std::io::Result<()> {
+        match self.query_label {
+            QueryLabel::Number => write!(print_stream, "{}", self.query_number)?,
+            QueryLabel::Id => write!(print_stream, "{}", self.query_id)?,
+            QueryLabel::Both => write!(print_stream, "{}\t{}", self.query_number, self.query_id)?,
+        }
+        write!(
+            print_stream,
+            "\t{}\t{}\t{}",
+            self.subject_numbers
+                .map_or(window_number, |numbers| numbers[window_number]),
+            distance,
+            subject
+        )?;
+        if let Some(offsets) = self.offsets {
+            write!(print_stream, "\t{}", offsets[window_number])?;
+        }
+        if let (Some(quality_scoring), Some(adjusted_distance)) =
+            (self.quality_scoring, adjusted_distance)
+        {
+            write!(
+                print_stream,
+                "\t{}",
+                quality_scoring.format(adjusted_distance)
+            )?;
+        }
+        if let Some(confidence) = &self.confidence {
+            let second_best_distance = match confidence.second_best_distance {
+                Some(d) => match self.quality_scoring {
+                    Some(quality_scoring) => quality_scoring.format(d),
+                    None => d.to_string(),
+                },
+                None => "NA".to_string(),
+            };
+            write!(
+                print_stream,
+                "\t{}\t{}",
+                second_best_distance, confidence.num_distinct_best_sequences
+            )?;
+        }
+        if let Some(database) = self.database {
+            write!(print_stream, "\t{}", database)?;
+        }
+        writeln!(print_stream)
+    }
+}
+
+/// Print the hits of a query against one database. When quality-adjusted
+/// distances are given, hits are chosen using them rather than the raw
+/// distances. Returns true if any hits were printed.
+fn report_hits(
+    windows: &WindowSet,
+    distances: &[usize],
+    adjusted_distances: Option<&[usize]>,
+    options: &QueryOptions,
+    hit_printer: &HitPrinter,
+    print_stream: &mut dyn Write,
+) -> Result<bool, Box<dyn Error>> {
+    let limit_per_sequence = options.limit_per_sequence;
+    let scores = adjusted_distances.unwrap_or(distances);
+    let max_divergence = options.max_divergence.map(|max_divergence| {
+        max_divergence as usize * options.quality_scoring.map_or(1, |q| q.scale())
+    });
+
+    // 1 is a special case, it is equivalent to None.
+    let max_num_hits_for_match = options
+        .max_num_hits
+        .filter(|&max_num_hits| max_num_hits != 1);
+
+    let mut any_hits = false;
+
+    // Find the max_num_hits'th minimum distance.
+    match max_num_hits_for_match {
+        Some(max_num_hits) => {
+            let mut min_distances = scores
+                .iter()
+                .enumerate()
+                .map(|(i, d)| (*d, i))
+                .collect::<Vec<_>>();
+            // There might be a faster way of doing this using a priority
+            // queue, but eh for now unless it really is slow.
+            min_distances.sort();
+
+            // If max num hits is greater than the number of windows, just print them all.
+            let max_distance = match max_num_hits > min_distances.len() as u32 {
+                true => *scores.iter().max().unwrap(),
+                false => min_distances[(max_num_hits - 1) as usize].0,
+            };
+
+            // Print out the windows that qualify in order of increasing distance.
+            let mut last_sequence: Option<(String, u32)> = None;
+            for (score, i) in min_distances.iter() {
+                if *score <= max_distance
+                    && (max_divergence.is_none() || *score <= max_divergence.unwrap())
+                {
+                    let s = windows.get_as_string(*i);
+                    debug!("Found hit sequence {} at distance {}", s, score);
+
+                    if let Some(limit_per_sequence_unwrapped) = limit_per_sequence {
+                        // limit per sequence
+                        let count = match &last_sequence {
+                            Some((last_seq, last_seq_count)) if last_seq == &s => {
+                                if *last_seq_count >= limit_per_sequence_unwrapped {
+                                    continue;
+                                }
+                                last_seq_count + 1
+                            }
+                            _ => 1,
+                        };
+                        last_sequence = Some((s.clone(), count));
+                    }
+
+                    // Print the window if we make it here.
+                    any_hits = true;
+                    hit_printer.print(
+                        print_stream,
+                        *i,
+                        distances[*i],
+                        adjusted_distances.map(|_| *score),
+                        &s,
+                    )?;
+                }
+            }
+        }
+        None => {
+            // Find the minimum distance.
+            let min_score = scores.iter().min().unwrap();
+            debug!("Min distance: {}", min_score);
+
+            if limit_per_sequence.is_some() {
+                panic!("limit_per_sequence is implemented unless max_num_hits > 1. It can be implemented by analogy, just haven't gotten around to it.");
+            }
+
+            // Print the windows with the minimum distance.
+            if max_divergence.is_none() || *min_score <= max_divergence.unwrap() {
+                for (i, score) in scores.iter().enumerate() {
+                    if score == min_score {
+                        let s = windows.get_as_string(i);
+                        any_hits = true;
+                        hit_printer.print(
+                            print_stream,
+                            i,
+                            distances[i],
+                            adjusted_distances.map(|_| *score),
+                            &s,
+                        )?;
+                    }
+                }
+            }
+        }
+    }
+    Ok(any_hits)
+}
+
 #[cfg(test)]
 mod tests {
     use std::str::FromStr;
@@ -354,7 +1298,7 @@ mod tests {
         assert_eq!(windows.windows.len(), 5);
 
         // Check that the first sequence has the expected one-hot encoded values.
-        let expected_encoded = vec![
+        let expected_encoded = [
             vec![0b10000],
             vec![0b01000],
             vec![0b00100],
@@ -365,6 +1309,106 @@ mod tests {
             assert_eq!(i, &j.0)
         }
     }
+
+    #[test]
+    fn test_parse_column_ranges() {
+        assert_eq!(vec![0, 1, 2, 9], parse_column_ranges("1-3,10").unwrap());
+        assert!(parse_column_ranges("0-3").is_err());
+        assert!(parse_column_ranges("3-1").is_err());
+        assert!(parse_column_ranges("a").is_err());
+    }
+
+    #[test]
+    fn test_weighted_distances() {
+        let mut windows = WindowSet::new(CURRENT_DB_VERSION);
+        windows.push_encoding(SeqEncodingLength::from_bytes(b"1", b"AAAAAAAAAAAAAC"));
+        let query = SeqEncodingLength::from_bytes(b"2", b"TAAAAAAAAAAAAG");
+        let mut distances = vec![0; 1];
+
+        windows.get_distances(&query, None, &mut distances);
+        assert_eq!(vec![2], distances);
+
+        // Mask the first column, and weight the last one, in the second chunk.
+        let mut weights = vec![1; 14];
+        weights[13] = 5;
+        let masks = ColumnWeights::new(vec![0], Some(weights))
+            .to_masks(14)
+            .unwrap()
+            .unwrap();
+        windows.get_distances(&query, Some(&masks), &mut distances);
+        assert_eq!(vec![5], distances);
+
+        assert!(ColumnWeights::default().to_masks(14).unwrap().is_none());
+        assert!(ColumnWeights::new(vec![14], None)
+            .check_masked_columns(14)
+            .is_err());
+        // Columns beyond the end are ignored when masking shorter sequences.
+        let masks = ColumnWeights::new(vec![0, 20], None)
+            .to_masks(14)
+            .unwrap()
+            .unwrap();
+        windows.get_distances(&query, Some(&masks), &mut distances);
+        assert_eq!(vec![1], distances);
+        assert!(ColumnWeights::new(vec![], Some(vec![1; 13]))
+            .to_masks(14)
+            .is_err());
+    }
+
+    #[test]
+    fn test_makedb_multi_marker() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db_path = temp_dir.path().join("test.db");
+        let manifest = temp_dir.path().join("manifest.tsv");
+        std::fs::write(
+            &manifest,
+            "m1\ttests/data/subjects.fa\nm2\ttests/data/random_3_2.fna\n",
+        )
+        .unwrap();
+
+        makedb_multi_marker(&manifest, &db_path).unwrap();
+        // The version is outside the range of single window set versions.
+        let version: u32 = postcard::from_bytes(&std::fs::read(&db_path).unwrap()).unwrap();
+        assert_eq!(CURRENT_MULTI_MARKER_DB_VERSION, version);
+        assert!(version >= MULTI_MARKER_DB_VERSION_BASE);
+
+        let targets = load_targets(&db_path).unwrap();
+        assert_eq!(
+            vec!["m1", "m2"],
+            targets.iter().map(|t| t.name.as_str()).collect::<Vec<_>>()
+        );
+        assert_eq!(5, targets[0].windows.windows.len());
+        assert_eq!(Some(1), targets[0].windows.len.map(NonZeroUsize::get));
+        assert_eq!("AGG", targets[1].windows.get_as_string(1));
+    }
+
+    #[test]
+    fn test_makedb_mixed_lengths() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db_path = temp_dir.path().join("test.db");
+        makedb(Path::new("tests/data/mixed_lengths.fna"), &db_path).unwrap();
+
+        let targets = load_targets(&db_path).unwrap();
+        assert_eq!(2, targets.len());
+        assert_eq!(Some(3), targets[0].windows.len.map(NonZeroUsize::get));
+        assert_eq!(Some(vec![0, 2]), targets[0].subject_numbers);
+        assert_eq!(Some(6), targets[1].windows.len.map(NonZeroUsize::get));
+        assert_eq!(Some(vec![1, 3]), targets[1].subject_numbers);
+        assert_eq!("AGGTGA", targets[1].windows.get_as_string(1));
+        assert_eq!(db_path.to_string_lossy(), targets[1].name);
+    }
+
+    #[test]
+    fn test_makedb_multi_marker_duplicate_marker() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let manifest = temp_dir.path().join("manifest.tsv");
+        std::fs::write(
+            &manifest,
+            "m1\ttests/data/subjects.fa\nm1\ttests/data/random_3_2.fna\n",
+        )
+        .unwrap();
+
+        assert!(makedb_multi_marker(&manifest, &temp_dir.path().join("test.db")).is_err());
+    }
 }
 
 // Derive IntoJson
@@ -3,6 +3,9 @@ use bird_tool_utils::clap_utils::set_log_level as set_log_level_bird_tool_utils;
 use clap::*;
 
 use std::env;
+use std::fs::File;
+use std::io::BufWriter;
+use std::num::NonZeroUsize;
 use std::path::PathBuf;
 
 use smafa::*;
@@ -16,32 +19,194 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         Some("query") => {
             let m = matches.subcommand_matches("query").unwrap();
             set_log_level(m, true);
-            let db_root = m.get_one::<PathBuf>("database").unwrap();
+            let mut db_paths = m
+                .get_many::<PathBuf>("database")
+                .map(|paths| paths.cloned().collect::<Vec<_>>())
+                .unwrap_or_default();
+            if let Some(database_list) = m.get_one::<PathBuf>("database-list") {
+                db_paths.extend(smafa::read_database_list(database_list)?);
+            }
             let query_fasta = m.get_one::<PathBuf>("query").unwrap();
-            let max_divergence = m.get_one::<u32>("max-divergence");
-            let max_num_hits = m.get_one::<u32>("max-num-hits");
-            let limit_per_sequence = m.get_one::<u32>("limit-per-sequence");
-            smafa::query(
-                db_root,
-                query_fasta,
-                max_divergence.copied(),
-                max_num_hits.copied(),
-                limit_per_sequence.copied(),
-            )
+            if let (Some(min_offset), Some(max_offset)) = (
+                m.get_one::<usize>("min-offset"),
+                m.get_one::<usize>("max-offset"),
+            ) {
+                if min_offset > max_offset {
+                    return Err(format!(
+                        "The min offset {} is greater than the max offset {}",
+                        min_offset, max_offset
+                    )
+                    .into());
+                }
+            }
+            let options = QueryOptions {
+                max_divergence: m.get_one::<u32>("max-divergence").copied(),
+                max_num_hits: m.get_one::<u32>("max-num-hits").copied(),
+                limit_per_sequence: m.get_one::<u32>("limit-per-sequence").copied(),
+                marker_header_field: m.get_one::<NonZeroUsize>("marker-header-field").copied(),
+                column_weights: parse_column_weights(m)?,
+                quality_scoring: match m.get_one::<u8>("quality-threshold") {
+                    Some(min_quality) => Some(QualityScoring::MinQuality(*min_quality)),
+                    None => m
+                        .get_flag("quality-weighting")
+                        .then_some(QualityScoring::ErrorProbability),
+                },
+                confidence_columns: m.get_flag("confidence-columns"),
+                unmatched_output: m.get_one::<PathBuf>("unmatched-output").cloned(),
+                novel_threshold: m.get_one::<u32>("novel-threshold").copied(),
+                novel_output: m.get_one::<PathBuf>("novel-output").cloned(),
+                query_label: match m.get_one::<String>("query-label").unwrap().as_str() {
+                    "number" => QueryLabel::Number,
+                    "id" => QueryLabel::Id,
+                    "both" => QueryLabel::Both,
+                    _ => unreachable!(),
+                },
+                database_column: m.get_flag("database-column")
+                    || m.get_one::<PathBuf>("database-list").is_some(),
+                sliding: m.get_flag("slide").then(|| OffsetRange {
+                    min: m.get_one::<usize>("min-offset").copied().unwrap_or(0),
+                    max: m.get_one::<usize>("max-offset").copied(),
+                }),
+            };
+            smafa::query(&db_paths, query_fasta, &options, &mut std::io::stdout())
         }
         Some("makedb") => {
             let m = matches.subcommand_matches("makedb").unwrap();
             set_log_level(m, true);
-            let subject_fasta = m.get_one::<PathBuf>("input").unwrap();
             let database = m.get_one::<PathBuf>("database").unwrap();
-            smafa::makedb(subject_fasta, database)
+            match m.get_one::<PathBuf>("manifest") {
+                Some(manifest) => smafa::makedb_multi_marker(manifest, database),
+                None => {
+                    let subject_fasta = m.get_one::<PathBuf>("input").unwrap();
+                    smafa::makedb(subject_fasta, database)
+                }
+            }
         }
         Some("cluster") => {
             let m = matches.subcommand_matches("cluster").unwrap();
             set_log_level(m, true);
             let input_fasta = m.get_one::<PathBuf>("input").unwrap();
-            let max_divergence = m.get_one::<u32>("max-divergence").unwrap();
-            smafa::cluster(input_fasta, *max_divergence, &mut std::io::stdout())
+            let max_divergences = m
+                .get_many::<u32>("max-divergence")
+                .map(|values| values.copied().collect::<Vec<_>>())
+                .unwrap_or_default();
+            let options = ClusterOptions {
+                max_divergence: max_divergences.first().copied().unwrap_or(0),
+                column_weights: parse_column_weights(m)?,
+                sort_by_abundance: m.get_flag("sort-by-abundance"),
+                cluster_sizes_output: m.get_one::<PathBuf>("cluster-sizes-output").cloned(),
+                membership_output: m.get_one::<PathBuf>("membership-output").cloned(),
+                centroids_output: m.get_one::<PathBuf>("centroids-output").cloned(),
+                centroids_db: m.get_one::<PathBuf>("centroids-db").cloned(),
+                reference_db: m.get_one::<PathBuf>("reference-db").cloned(),
+                closed_reference: m.get_flag("closed-reference"),
+                unassigned_output: m.get_one::<PathBuf>("unassigned-output").cloned(),
+                reassign: m.get_flag("reassign"),
+                coarser_divergences: max_divergences.iter().skip(1).copied().collect(),
+                hierarchy_output: m.get_one::<PathBuf>("hierarchy-output").cloned(),
+                single_linkage: m.get_flag("single-linkage"),
+                threads: *m.get_one::<usize>("threads").unwrap(),
+                consensus: m.get_flag("consensus"),
+                consensus_support_output: m.get_one::<PathBuf>("consensus-support-output").cloned(),
+                stats_output: m.get_one::<PathBuf>("stats-output").cloned(),
+                min_cluster_size: *m.get_one::<usize>("min-cluster-size").unwrap(),
+                small_cluster_max_divergence: m
+                    .get_one::<u32>("small-cluster-max-divergence")
+                    .copied(),
+                k_medoids: m.get_one::<usize>("k-medoids").copied(),
+                seed: m.get_one::<u64>("seed").copied().unwrap_or(0),
+            };
+            smafa::cluster(input_fasta, &options, &mut std::io::stdout())
+        }
+        Some("filter") => {
+            let m = matches.subcommand_matches("filter").unwrap();
+            set_log_level(m, true);
+            let db_paths = m
+                .get_many::<PathBuf>("database")
+                .unwrap()
+                .cloned()
+                .collect::<Vec<_>>();
+            let input = m.get_one::<PathBuf>("input").unwrap();
+            let options = FilterOptions {
+                max_divergence: *m.get_one::<u32>("max-divergence").unwrap(),
+                inverse: m.get_flag("inverse"),
+                column_weights: parse_column_weights(m)?,
+            };
+            match m.get_one::<PathBuf>("output") {
+                Some(output) => smafa::filter(
+                    &db_paths,
+                    input,
+                    &options,
+                    &mut BufWriter::new(File::create(output)?),
+                ),
+                None => smafa::filter(
+                    &db_paths,
+                    input,
+                    &options,
+                    &mut BufWriter::new(std::io::stdout()),
+                ),
+            }
+        }
+        Some("derep") => {
+            let m = matches.subcommand_matches("derep").unwrap();
+            set_log_level(m, true);
+            let input = m.get_one::<PathBuf>("input").unwrap();
+            let mapping_output = m.get_one::<PathBuf>("mapping-output");
+            match m.get_one::<PathBuf>("output") {
+                Some(output) => smafa::derep(
+                    input,
+                    &mut BufWriter::new(File::create(output)?),
+                    mapping_output.map(PathBuf::as_path),
+                ),
+                None => smafa::derep(
+                    input,
+                    &mut BufWriter::new(std::io::stdout()),
+                    mapping_output.map(PathBuf::as_path),
+                ),
+            }
+        }
+        Some("denoise") => {
+            let m = matches.subcommand_matches("denoise").unwrap();
+            set_log_level(m, true);
+            let input = m.get_one::<PathBuf>("input").unwrap();
+            let mapping_output = m.get_one::<PathBuf>("mapping-output");
+            let options = DenoiseOptions {
+                alpha: *m.get_one::<f64>("alpha").unwrap(),
+                min_size: *m.get_one::<usize>("min-size").unwrap(),
+                column_weights: parse_column_weights(m)?,
+            };
+            match m.get_one::<PathBuf>("output") {
+                Some(output) => smafa::denoise(
+                    input,
+                    &options,
+                    &mut BufWriter::new(File::create(output)?),
+                    mapping_output.map(PathBuf::as_path),
+                ),
+                None => smafa::denoise(
+                    input,
+                    &options,
+                    &mut BufWriter::new(std::io::stdout()),
+                    mapping_output.map(PathBuf::as_path),
+                ),
+            }
+        }
+        Some("chimera") => {
+            let m = matches.subcommand_matches("chimera").unwrap();
+            set_log_level(m, true);
+            let input = m.get_one::<PathBuf>("input").unwrap();
+            let options = ChimeraOptions {
+                reference_db: m.get_one::<PathBuf>("reference-db").cloned(),
+                min_parent_skew: *m.get_one::<f64>("min-parent-skew").unwrap(),
+                max_divergence: *m.get_one::<u32>("max-divergence").unwrap(),
+                min_improvement: *m.get_one::<u32>("min-improvement").unwrap(),
+                column_weights: parse_column_weights(m)?,
+            };
+            match m.get_one::<PathBuf>("output") {
+                Some(output) => {
+                    smafa::chimera(input, &options, &mut BufWriter::new(File::create(output)?))
+                }
+                None => smafa::chimera(input, &options, &mut BufWriter::new(std::io::stdout())),
+            }
         }
         Some("count") => {
             let m = matches.subcommand_matches("count").unwrap();
@@ -57,6 +222,27 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     }
 }
 
+fn parse_column_weights(m: &clap::ArgMatches) -> Result<ColumnWeights, Box<dyn std::error::Error>> {
+    let masked_columns = match m.get_one::<String>("mask-columns") {
+        Some(ranges) => smafa::parse_column_ranges(ranges)?,
+        None => vec![],
+    };
+    let weights = match m.get_one::<PathBuf>("column-weights") {
+        Some(path) => Some(smafa::read_column_weights(path)?),
+        None => None,
+    };
+    Ok(ColumnWeights::new(masked_columns, weights))
+}
+
+fn add_column_weight_args(command: Command) -> Command {
+    command
+        .arg(arg!(--"mask-columns" <RANGES> "Columns to ignore when calculating divergence, counting from 1 e.g. 1-5,20 [default: not used]"))
+        .arg(
+            arg!(--"column-weights" <FILE> "File of integer weights, one per column separated by whitespace or commas, used to calculate a weighted divergence [default: not used]")
+                .value_parser(value_parser!(PathBuf)),
+        )
+}
+
 fn set_log_level(matches: &clap::ArgMatches, is_last: bool) {
     set_log_level_bird_tool_utils(matches, is_last, "Smafa", crate_version!());
 }
@@ -69,42 +255,161 @@ fn build_cli() -> Command {
         .subcommand(add_clap_verbosity_flags(
             Command::new("makedb")
                 .about("Generate a searchable database")
-                .arg(arg!(-i --input <FILE> "Subject sequences to search against [required]").required(true).value_parser(value_parser!(PathBuf)))
+                .arg(arg!(-i --input <FILE> "Subject sequences to search against [required unless --manifest is given]").required_unless_present("manifest").value_parser(value_parser!(PathBuf)))
+                .arg(arg!(--manifest <FILE> "Make a multi-marker database from a file where each line is a marker name and the path to its subject sequences, separated by a tab").conflicts_with("input").value_parser(value_parser!(PathBuf)))
                 .arg(arg!(-d --database <FILE> "Output DB filename [required]").required(true).value_parser(value_parser!(PathBuf)))
         ))
-        .subcommand(add_clap_verbosity_flags(
+        .subcommand(add_clap_verbosity_flags(add_column_weight_args(
             Command::new("query")
                 .about("Search a database. See query --help for more information about output format.")
                 .long_about("This command searches a database for query sequences. The database must be generated with the `makedb` command. The query sequences can be in FASTA or FASTQ format. The output is a tab-separated file with the following columns:\n\
                 \n\
-                1. Query sequence number (0-indexed)\n\
+                1. Query sequence number (0-indexed), or with --query-label id, the query identifier (the header up to the first whitespace). With --query-label both, the number and identifier are given in separate columns\n\
                 2. Subject sequence number (0-indexed)\n\
-                3. Divergence (number of nucleotides different between the two sequences\n\
-                4. Subject sequence (with dashes and degenerate base symbols shown as Ns)")
-                .arg(arg!(-d --database <FILE> "Output from makedb [required]").required(true).value_parser(value_parser!(PathBuf)))
+                3. Divergence (number of nucleotides different between the two sequences, excluding --mask-columns and weighted by --column-weights)\n\
+                4. Subject sequence (with dashes and degenerate base symbols shown as Ns)\n\
+                \n\
+                These columns follow when requested, in this order:\n\
+                \n\
+                - Offset of the query where the subject window starts, counting from 0 (with --slide)\n\
+                - Quality-adjusted divergence (with --quality-threshold or --quality-weighting)\n\
+                - Divergence of the best hit with a different sequence to the best hit, or NA if there is none (with --confidence-columns)\n\
+                - Number of distinct subject sequences at the best divergence (with --confidence-columns)\n\
+                - Database the hit was found in, or the marker name for multi-marker databases (when more than one database or marker is searched)\n\
+                \n\
+                Each query is searched against every database or marker with windows of the same length as the query. With --slide, queries are instead searched against windows no longer than the query, at each offset of the query, and the best offset for each subject is reported. With --marker-header-field, only the marker named in that field of the query header is searched.")
+                .arg(arg!(-d --database <FILE> "Output from makedb. Can be specified multiple times [required unless --database-list is given]").required_unless_present("database-list").num_args(1..).action(ArgAction::Append).value_parser(value_parser!(PathBuf)))
+                .arg(arg!(--"database-list" <FILE> "File containing paths of databases to search, one per line").value_parser(value_parser!(PathBuf)))
+                .arg(arg!(--"database-column" "Add a column giving the database each hit came from. This is always added when more than one database is given, or with --database-list"))
                 .arg(arg!(-q --query <FILE> "Query sequences to search with in FASTX format [required]").required(true).value_parser(value_parser!(PathBuf)))
                 .arg(
                     arg!( --"max-divergence" <INT> "Maximum divergence to report hits for, for each sequence [default: not used]")
                         .value_parser(value_parser!(u32)),
                 )
+                .arg(
+                    arg!( --"query-label" <LABEL> "Identify queries in the output by their number (0-indexed), their identifier, or both")
+                        .value_parser(["number", "id", "both"])
+                        .default_value("number"),
+                )
                 .arg(
                     arg!( --"max-num-hits" <INT> "Maximum number of hits to report [default: 1]")
                         .value_parser(value_parser!(u32)),
                 )
+                .arg(
+                    arg!( --"marker-header-field" <INT> "Search only the marker named in this whitespace-separated field of each query header, counting from 1 [default: not used]")
+                        .value_parser(value_parser!(NonZeroUsize)),
+                )
+                .arg(
+                    arg!( --"quality-threshold" <INT> "Ignore query bases with phred quality scores below this threshold. Hits are chosen by quality-adjusted divergence [default: not used]")
+                        .value_parser(value_parser!(u8)),
+                )
+                .arg(
+                    arg!( --"quality-weighting" "Weight each mismatch by the probability that the query base is correct, according to its quality score. Hits are chosen by quality-adjusted divergence")
+                        .conflicts_with("quality-threshold"),
+                )
+                .arg(arg!( --slide "Search queries longer than the database windows by sliding each window across the query without gaps"))
+                .arg(
+                    arg!( --"min-offset" <INT> "With --slide, first offset of the query to compare windows at, counting from 0 [default: 0]")
+                        .requires("slide")
+                        .value_parser(value_parser!(usize)),
+                )
+                .arg(
+                    arg!( --"max-offset" <INT> "With --slide, last offset of the query to compare windows at, counting from 0 [default: the last offset where the window fits]")
+                        .requires("slide")
+                        .value_parser(value_parser!(usize)),
+                )
+                .arg(arg!( --"confidence-columns" "Add columns for the divergence of the second best distinct hit and the number of distinct subject sequences at the best divergence"))
+                .arg(
+                    arg!( --"unmatched-output" <FILE> "Write queries without any hits to this file, in their original FASTA/FASTQ format [default: not used]")
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    arg!( --"novel-threshold" <INT> "Write queries whose best hit has divergence greater than this to --novel-output [default: not used]")
+                        .requires("novel-output")
+                        .value_parser(value_parser!(u32)),
+                )
+                .arg(
+                    arg!( --"novel-output" <FILE> "Output file for --novel-threshold, in the original FASTA/FASTQ format")
+                        .requires("novel-threshold")
+                        .value_parser(value_parser!(PathBuf)),
+                )
                 .arg(
                     arg!( --"limit-per-sequence" <INT> "Maximum number of hits to report per sequence. Requires --max-num-hits > 1 for now. [default: not used]")
                         .value_parser(value_parser!(u32)),
                 ),
-        ))
-        .subcommand(add_clap_verbosity_flags(
+        )))
+        .subcommand(add_clap_verbosity_flags(add_column_weight_args(
             Command::new("cluster")
                 .about("Cluster sequences by similarity")
                 .arg(arg!(-i --input <FILE> "FASTA file to cluster [required]").required(true).value_parser(value_parser!(PathBuf)))
                 .arg(
-                    arg!(-d --"max-divergence" <INT> "Maximum divergence to report hits for, for each sequence [default: not used]")
+                    arg!(-d --"max-divergence" <INT> "Maximum divergence to report hits for, for each sequence. A comma-separated list of increasing divergences clusters the centroids of each divergence at the next, see --hierarchy-output [required unless --k-medoids is specified]")
+                        .required_unless_present("k-medoids")
+                        .value_delimiter(',')
                         .value_parser(value_parser!(u32)),
-                ),
+                )
+                .arg(arg!(--"sort-by-abundance" "Process sequences in order of decreasing abundance, taken from \";size=N\" annotations and counts of identical sequences, instead of input order"))
+                .arg(arg!(--"cluster-sizes-output" <FILE> "Write a tab-separated file of each cluster number, centroid and total abundance of its members [default: not used]").value_parser(value_parser!(PathBuf)))
+                .arg(arg!(--"membership-output" <FILE> "Write a tab-separated file with a line for every input sequence giving its identifier, cluster number, centroid identifier, distance to the centroid, and whether it is a duplicate of an earlier sequence [default: not used]").value_parser(value_parser!(PathBuf)))
+                .arg(arg!(--"centroids-output" <FILE> "Write the centroid of each cluster in FASTA format, with the total abundance of the cluster as a \";size=N\" annotation [default: not used]").value_parser(value_parser!(PathBuf)))
+                .arg(arg!(--"centroids-db" <FILE> "Write the centroids as a database which can be searched with query. Subject numbers are cluster numbers [default: not used]").value_parser(value_parser!(PathBuf)))
+                .arg(arg!(--"reference-db" <FILE> "Database from makedb or --centroids-db whose windows are the first centroids, keeping their cluster numbers. Input sequences which do not match a reference centroid start new clusters unless --closed-reference is specified [default: not used]").value_parser(value_parser!(PathBuf)))
+                .arg(arg!(--"closed-reference" "Do not start new clusters, leaving sequences which do not match a reference centroid unassigned").requires("reference-db"))
+                .arg(arg!(--"unassigned-output" <FILE> "Write input sequences which are not assigned to a cluster in FASTA format [default: not used]").value_parser(value_parser!(PathBuf)))
+                .arg(arg!(--"reassign" "After clustering, move each sequence to its closest centroid, which may have been created after it was assigned. The membership output then has an extra column saying whether each sequence moved"))
+                .arg(arg!(--"hierarchy-output" <FILE> "Write a tab-separated table with a header, giving the cluster of every input sequence at each max divergence. Other outputs are for the smallest max divergence. Required when more than one max divergence is given [default: not used]").value_parser(value_parser!(PathBuf)))
+                .arg(arg!(--"single-linkage" "Make clusters the connected components of sequences within the max divergence of each other, rather than clustering greedily. The centroid of each cluster is its first member").conflicts_with_all(["reference-db", "reassign"]))
+                .arg(arg!(-t --threads <INT> "Number of threads to use. Results are the same for any number of threads").default_value("1").value_parser(value_parser!(usize)))
+                .arg(arg!(--consensus "Write the per-column majority consensus of each cluster's members, weighted by abundance, in place of its centroid with --centroids-output and --centroids-db. Tied columns are given as N"))
+                .arg(arg!(--"consensus-support-output" <FILE> "Write a tab-separated file of each cluster number, centroid identifier, and the comma-separated fraction of the cluster's abundance agreeing with each column of its consensus [default: not used]").requires("consensus").value_parser(value_parser!(PathBuf)))
+                .arg(arg!(--"stats-output" <FILE> "Write summary statistics in JSON format, including the number of sequences, duplicates, clusters and singletons, the distribution of cluster sizes, the mean and max distance to the centroid of each cluster, and the runtime [default: not used]").value_parser(value_parser!(PathBuf)))
+                .arg(arg!(--"min-cluster-size" <INT> "Remove clusters with a smaller total abundance than this, leaving their members unassigned. Clusters of reference centroids are kept. The remaining clusters are renumbered in order").default_value("1").value_parser(value_parser!(usize)))
+                .arg(arg!(--"small-cluster-max-divergence" <INT> "Move members of clusters removed by --min-cluster-size to the closest remaining centroid within this divergence, which must be at least --max-divergence, rather than leaving them unassigned [default: not used]").requires("min-cluster-size").value_parser(value_parser!(u32)))
+                .arg(arg!(--"k-medoids" <K> "Partition sequences into K clusters around medoids, ignoring --max-divergence. Initial medoids are chosen at random, favouring sequences distant from those already chosen, then sequences are assigned to their closest medoid and medoids moved to the member closest to the rest of their cluster until they do not change [default: not used]").conflicts_with_all(["max-divergence", "single-linkage", "reference-db", "reassign"]).value_parser(value_parser!(usize)))
+                .arg(arg!(--"seed" <INT> "Random seed for choosing initial medoids with --k-medoids [default: 0]").requires("k-medoids").conflicts_with("max-divergence").value_parser(value_parser!(u64))),
+        )))
+        .subcommand(add_clap_verbosity_flags(add_column_weight_args(
+            Command::new("filter")
+                .about("Keep reads which match a database, or with --inverse, those which do not")
+                .arg(arg!(-d --database <FILE> "Output from makedb. Can be specified multiple times [required]").required(true).num_args(1..).action(ArgAction::Append).value_parser(value_parser!(PathBuf)))
+                .arg(arg!(-i --input <FILE> "Reads to filter in FASTX format [required]").required(true).value_parser(value_parser!(PathBuf)))
+                .arg(arg!(-o --output <FILE> "Write kept reads to this file in their original format [default: stdout]").value_parser(value_parser!(PathBuf)))
+                .arg(
+                    arg!(--"max-divergence" <INT> "Reads with a hit at this divergence or less match [required]")
+                        .required(true)
+                        .value_parser(value_parser!(u32)),
+                )
+                .arg(arg!(--inverse "Keep reads which do not match instead of those that do")),
+        )))
+        .subcommand(add_clap_verbosity_flags(
+            Command::new("derep")
+                .about("Collapse identical sequences, annotating each with its abundance")
+                .long_about("Collapse identical sequences. One representative of each unique sequence is written in FASTA format, with its abundance given as a vsearch-style \";size=N\" annotation, most abundant first. The abundance of input sequences is taken from any \";size=N\" annotation, otherwise it is 1.")
+                .arg(arg!(-i --input <FILE> "Sequences to dereplicate in FASTX format [required]").required(true).value_parser(value_parser!(PathBuf)))
+                .arg(arg!(-o --output <FILE> "Write unique sequences to this file [default: stdout]").value_parser(value_parser!(PathBuf)))
+                .arg(arg!(--"mapping-output" <FILE> "Write a tab-separated file of each input sequence identifier and the identifier of its representative [default: not used]").value_parser(value_parser!(PathBuf))),
         ))
+        .subcommand(add_clap_verbosity_flags(add_column_weight_args(
+            Command::new("denoise")
+                .about("Denoise sequences into exact sequence variants, UNOISE-style")
+                .long_about("Denoise sequences into exact sequence variants. Unique sequences are considered in order of decreasing abundance, taken from \";size=N\" annotations and counts of identical sequences. Each is absorbed into the most abundant variant with an abundance skew of at most 1/2^(alpha * divergence + 1), as in UNOISE. Otherwise it becomes a new variant if its abundance is at least --min-size, or is discarded. Variants are written in FASTA format with the total abundance of the sequences absorbed into them as a \";size=N\" annotation.")
+                .arg(arg!(-i --input <FILE> "Sequences to denoise in FASTX format [required]").required(true).value_parser(value_parser!(PathBuf)))
+                .arg(arg!(-o --output <FILE> "Write variants to this file [default: stdout]").value_parser(value_parser!(PathBuf)))
+                .arg(arg!(--"mapping-output" <FILE> "Write a tab-separated file of each input sequence identifier and the identifier of its variant, or NA if it was discarded [default: not used]").value_parser(value_parser!(PathBuf)))
+                .arg(arg!(--alpha <FLOAT> "How quickly the abundance skew required to absorb a sequence falls with divergence").default_value("2.0").value_parser(value_parser!(f64)))
+                .arg(arg!(--"min-size" <INT> "Minimum abundance of a variant").default_value("8").value_parser(value_parser!(usize))),
+        )))
+        .subcommand(add_clap_verbosity_flags(add_column_weight_args(
+            Command::new("chimera")
+                .about("Flag sequences which are chimeras of two parent sequences")
+                .long_about("Flag sequences which are better explained as a chimera of two parents than as derived from one. The columns before a breakpoint are compared to one parent and the rest to another. Parents are the input sequences at least --min-parent-skew times as abundant which are not themselves chimeras, or the windows of --reference-db. Abundances are taken from \";size=N\" annotations and counts of identical sequences.\n\nFor each input sequence, a tab-separated line is written with the columns:\n1. sequence identifier\n2. whether it is a chimera\n3. closest single parent\n4. divergence from the closest single parent\n5. left parent\n6. right parent\n7. breakpoint, the number of columns from the left parent\n8. divergence from the two parents\nParents from a reference database are named by their number in it, e.g. reference_0. NA is given where there are no parents.")
+                .arg(arg!(-i --input <FILE> "Sequences to search in FASTX format [required]").required(true).value_parser(value_parser!(PathBuf)))
+                .arg(arg!(-o --output <FILE> "Write results to this file [default: stdout]").value_parser(value_parser!(PathBuf)))
+                .arg(arg!(--"reference-db" <FILE> "Database from makedb whose windows are the parents, instead of more abundant input sequences [default: not used]").value_parser(value_parser!(PathBuf)))
+                .arg(arg!(--"min-parent-skew" <FLOAT> "Without a reference, parents must be at least this many times as abundant as the sequence").default_value("2.0").value_parser(value_parser!(f64)))
+                .arg(arg!(--"max-divergence" <INT> "Maximum divergence of a chimera from its two parents").default_value("1").value_parser(value_parser!(u32)))
+                .arg(arg!(--"min-improvement" <INT> "Minimum amount by which the divergence from two parents must be less than from the closest single parent").default_value("2").value_parser(value_parser!(u32))),
+        )))
         .subcommand(add_clap_verbosity_flags(
             Command::new("count")
                 .about("Print the number of reads/bases in a possibly gzipped FASTX file")
@@ -246,6 +246,967 @@ mod tests {
             .unwrap()
     }
 
+    #[test]
+    fn test_query_multiple_databases() {
+        let tf: tempfile::NamedTempFile = tempfile::NamedTempFile::new().unwrap();
+        let t = tf.path().to_str().unwrap();
+        Assert::main_binary()
+            .with_args(&["makedb", "-i", "tests/data/degenerate.fna", "-d", t])
+            .succeeds()
+            .unwrap();
+
+        // The degenerate db has windows of length 6, so only the other two
+        // databases are searched.
+        Assert::main_binary()
+            .with_args(&[
+                "query",
+                "-d",
+                "tests/data/random_3_2.fna.smafadb",
+                "-d",
+                t,
+                "tests/data/random_3_2_one_repeated.fna.smafadb",
+                "-q",
+                "tests/data/random_3_2.fna",
+            ])
+            .succeeds()
+            .stdout()
+            .is("0	0	0	CTT	tests/data/random_3_2.fna.smafadb\n\
+                0	0	0	CTT	tests/data/random_3_2_one_repeated.fna.smafadb\n\
+                1	1	0	AGG	tests/data/random_3_2.fna.smafadb\n\
+                1	1	0	AGG	tests/data/random_3_2_one_repeated.fna.smafadb\n\
+                1	2	0	AGG	tests/data/random_3_2_one_repeated.fna.smafadb\n")
+            .unwrap()
+    }
+
+    #[test]
+    fn test_query_database_column() {
+        Assert::main_binary()
+            .with_args(&[
+                "query",
+                "-d",
+                "tests/data/random_3_2.fna.smafadb",
+                "-q",
+                "tests/data/random_3_2.fna",
+                "--database-column",
+            ])
+            .succeeds()
+            .stdout()
+            .is("0	0	0	CTT	tests/data/random_3_2.fna.smafadb\n\
+                1	1	0	AGG	tests/data/random_3_2.fna.smafadb\n")
+            .unwrap()
+    }
+
+    #[test]
+    fn test_query_database_list() {
+        let td = tempfile::TempDir::new().unwrap();
+        let list = td.path().join("dbs.txt");
+        std::fs::write(
+            &list,
+            "# databases\ntests/data/random_3_2.fna.smafadb\n\ntests/data/random_3_2_one_repeated.fna.smafadb\n",
+        )
+        .unwrap();
+
+        Assert::main_binary()
+            .with_args(&[
+                "query",
+                "--database-list",
+                list.to_str().unwrap(),
+                "-q",
+                "tests/data/random_3_2.fna",
+                "--max-num-hits",
+                "2",
+            ])
+            .succeeds()
+            .stdout()
+            .is("0	0	0	CTT	tests/data/random_3_2.fna.smafadb\n\
+                0	1	3	AGG	tests/data/random_3_2.fna.smafadb\n\
+                0	0	0	CTT	tests/data/random_3_2_one_repeated.fna.smafadb\n\
+                0	1	3	AGG	tests/data/random_3_2_one_repeated.fna.smafadb\n\
+                0	2	3	AGG	tests/data/random_3_2_one_repeated.fna.smafadb\n\
+                1	1	0	AGG	tests/data/random_3_2.fna.smafadb\n\
+                1	0	3	CTT	tests/data/random_3_2.fna.smafadb\n\
+                1	1	0	AGG	tests/data/random_3_2_one_repeated.fna.smafadb\n\
+                1	2	0	AGG	tests/data/random_3_2_one_repeated.fna.smafadb\n")
+            .unwrap()
+    }
+
+    #[test]
+    fn test_query_empty_database_list() {
+        let td = tempfile::TempDir::new().unwrap();
+        let list = td.path().join("dbs.txt");
+        std::fs::write(&list, "# no databases\n\n").unwrap();
+        Assert::main_binary()
+            .with_args(&[
+                "query",
+                "--database-list",
+                list.to_str().unwrap(),
+                "-q",
+                "tests/data/random_3_2.fna",
+            ])
+            .fails()
+            .stderr()
+            .contains("No databases were given to search")
+            .unwrap()
+    }
+
+    #[test]
+    fn test_query_no_database_of_query_length() {
+        Assert::main_binary()
+            .with_args(&[
+                "query",
+                "-d",
+                "tests/data/random_3_2.fna.smafadb",
+                "-q",
+                "tests/data/degenerate.fna",
+            ])
+            .succeeds()
+            .stdout()
+            .is("")
+            .unwrap()
+    }
+
+    #[test]
+    fn test_query_mask_columns() {
+        Assert::main_binary()
+            .with_args(&[
+                "query",
+                "-d",
+                "tests/data/random_3_2.fna.smafadb",
+                "-q",
+                "tests/data/random_3_2.fna",
+                "--max-num-hits",
+                "99",
+                "--mask-columns",
+                "1-2",
+            ])
+            .succeeds()
+            .stdout()
+            .is("0	0	0	CTT\n\
+                0	1	1	AGG\n\
+                1	1	0	AGG\n\
+                1	0	1	CTT\n")
+            .unwrap()
+    }
+
+    #[test]
+    fn test_query_mask_columns_mixed_lengths() {
+        let td = tempfile::TempDir::new().unwrap();
+        let windows = td.path().join("windows.fna");
+        let queries = td.path().join("queries.fna");
+        let db = td.path().join("mixed.db");
+        std::fs::write(&windows, ">short\nAAAAAAAA\n>long\nAAAAAAAAAAAAAA\n").unwrap();
+        std::fs::write(&queries, ">q1\nAAAAAAAC\n>q2\nAAAAAAAAACCCAA\n").unwrap();
+        Assert::main_binary()
+            .with_args(&[
+                "makedb",
+                "-i",
+                windows.to_str().unwrap(),
+                "-d",
+                db.to_str().unwrap(),
+            ])
+            .succeeds()
+            .unwrap();
+        // The masked columns are beyond the end of the short windows, which
+        // are compared unmasked.
+        Assert::main_binary()
+            .with_args(&[
+                "query",
+                "-d",
+                db.to_str().unwrap(),
+                "-q",
+                queries.to_str().unwrap(),
+                "--mask-columns",
+                "10-12",
+            ])
+            .succeeds()
+            .stdout()
+            .is("0\t0\t1\tAAAAAAAA\n\
+                1\t1\t0\tAAAAAAAAAAAAAA\n")
+            .unwrap();
+        Assert::main_binary()
+            .with_args(&[
+                "query",
+                "-d",
+                db.to_str().unwrap(),
+                "-q",
+                queries.to_str().unwrap(),
+                "--mask-columns",
+                "15",
+            ])
+            .fails()
+            .stderr()
+            .contains("Cannot mask column 15 since the sequences have length at most 14")
+            .unwrap();
+    }
+
+    #[test]
+    fn test_query_column_weights() {
+        let td = tempfile::TempDir::new().unwrap();
+        let weights = td.path().join("weights.txt");
+        std::fs::write(&weights, "1 1\n5\n").unwrap();
+        Assert::main_binary()
+            .with_args(&[
+                "query",
+                "-d",
+                "tests/data/random_3_2.fna.smafadb",
+                "-q",
+                "tests/data/random_3_2.fna",
+                "--max-num-hits",
+                "99",
+                "--max-divergence",
+                "6",
+                "--column-weights",
+                weights.to_str().unwrap(),
+                "--mask-columns",
+                "2",
+            ])
+            .succeeds()
+            .stdout()
+            .is("0	0	0	CTT\n\
+                0	1	6	AGG\n\
+                1	1	0	AGG\n\
+                1	0	6	CTT\n")
+            .unwrap()
+    }
+
+    #[test]
+    fn test_query_quality_threshold() {
+        Assert::main_binary()
+            .with_args(&[
+                "query",
+                "-d",
+                "tests/data/random_3_2.fna.smafadb",
+                "-q",
+                "tests/data/random_3_2_low_quality.fq",
+            ])
+            .succeeds()
+            .stdout()
+            .is("0	0	1	CTT\n\
+                1	1	1	AGG\n")
+            .unwrap();
+
+        // The low quality mismatches of the second read are ignored, so it
+        // is assigned to the other subject.
+        Assert::main_binary()
+            .with_args(&[
+                "query",
+                "-d",
+                "tests/data/random_3_2.fna.smafadb",
+                "-q",
+                "tests/data/random_3_2_low_quality.fq",
+                "--quality-threshold",
+                "20",
+            ])
+            .succeeds()
+            .stdout()
+            .is("0	0	1	CTT	0\n\
+                1	0	2	CTT	0\n")
+            .unwrap()
+    }
+
+    #[test]
+    fn test_query_quality_weighting() {
+        Assert::main_binary()
+            .with_args(&[
+                "query",
+                "-d",
+                "tests/data/random_3_2.fna.smafadb",
+                "-q",
+                "tests/data/random_3_2_low_quality.fq",
+                "--quality-weighting",
+                "--max-num-hits",
+                "99",
+                "--max-divergence",
+                "1",
+            ])
+            .succeeds()
+            .stdout()
+            .is("0	0	1	CTT	0.37\n\
+                1	0	2	CTT	0.74\n\
+                1	1	1	AGG	1.00\n")
+            .unwrap()
+    }
+
+    #[test]
+    fn test_query_confidence_columns() {
+        Assert::main_binary()
+            .with_args(&[
+                "query",
+                "-d",
+                "tests/data/random_3_2_one_repeated.fna.smafadb",
+                "-q",
+                "tests/data/random_3_2.fna",
+                "--confidence-columns",
+            ])
+            .succeeds()
+            .stdout()
+            .is("0	0	0	CTT	3	1\n\
+                1	1	0	AGG	3	1\n\
+                1	2	0	AGG	3	1\n")
+            .unwrap()
+    }
+
+    #[test]
+    fn test_query_confidence_columns_tied_best() {
+        // Both subjects are 2 away from the query once the last column is
+        // masked, so the second best distinct hit is also 2 away.
+        let td = tempfile::TempDir::new().unwrap();
+        let query = td.path().join("query.fna");
+        std::fs::write(&query, ">q1\nGAG\n").unwrap();
+        Assert::main_binary()
+            .with_args(&[
+                "query",
+                "-d",
+                "tests/data/random_3_2.fna.smafadb",
+                "-q",
+                query.to_str().unwrap(),
+                "--confidence-columns",
+                "--mask-columns",
+                "3",
+            ])
+            .succeeds()
+            .stdout()
+            .is("0	0	2	CTT	2	2\n\
+                0	1	2	AGG	2	2\n")
+            .unwrap()
+    }
+
+    #[test]
+    fn test_query_unmatched_and_novel_output() {
+        let td = tempfile::TempDir::new().unwrap();
+        let query = td.path().join("query.fq");
+        std::fs::write(
+            &query,
+            "@match\nCTT\n+\nIII\n@near\nCTA\n+\nII#\n@far read\nGAA\n+\nIII\n@short\nCT\n+\nII\n",
+        )
+        .unwrap();
+        let unmatched = td.path().join("unmatched.fq");
+        let novel = td.path().join("novel.fq");
+
+        Assert::main_binary()
+            .with_args(&[
+                "query",
+                "-d",
+                "tests/data/random_3_2.fna.smafadb",
+                "-q",
+                query.to_str().unwrap(),
+                "--max-divergence",
+                "1",
+                "--unmatched-output",
+                unmatched.to_str().unwrap(),
+                "--novel-threshold",
+                "0",
+                "--novel-output",
+                novel.to_str().unwrap(),
+            ])
+            .succeeds()
+            .stdout()
+            .is("0	0	0	CTT\n\
+                1	0	1	CTT\n")
+            .unwrap();
+
+        assert_eq!(
+            "@far read\nGAA\n+\nIII\n@short\nCT\n+\nII\n",
+            std::fs::read_to_string(unmatched).unwrap()
+        );
+        assert_eq!(
+            "@near\nCTA\n+\nII#\n@far read\nGAA\n+\nIII\n",
+            std::fs::read_to_string(novel).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_filter_inverse_to_file() {
+        let td = tempfile::TempDir::new().unwrap();
+        let output = td.path().join("out.fq");
+        Assert::main_binary()
+            .with_args(&[
+                "filter",
+                "-d",
+                "tests/data/random_3_2.fna.smafadb",
+                "-i",
+                "tests/data/random_3_2_low_quality.fq",
+                "--max-divergence",
+                "0",
+                "--inverse",
+                "-o",
+                output.to_str().unwrap(),
+            ])
+            .succeeds()
+            .stdout()
+            .is("")
+            .unwrap();
+        assert_eq!(
+            "@q1\nCTG\n+\nII#\n@q2\nAGT\n+\n##I\n",
+            std::fs::read_to_string(output).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_query_label() {
+        Assert::main_binary()
+            .with_args(&[
+                "query",
+                "-d",
+                "tests/data/random_3_2.fna.smafadb",
+                "-q",
+                "tests/data/random_3_2.fna",
+                "--query-label",
+                "id",
+            ])
+            .succeeds()
+            .stdout()
+            .is("random_sequence_length_3_1	0	0	CTT\n\
+                random_sequence_length_3_2	1	0	AGG\n")
+            .unwrap();
+
+        // Only the header up to the first whitespace is reported.
+        let td = tempfile::TempDir::new().unwrap();
+        let query = td.path().join("query.fna");
+        std::fs::write(&query, ">q1 some description\nAGG\n").unwrap();
+        Assert::main_binary()
+            .with_args(&[
+                "query",
+                "-d",
+                "tests/data/random_3_2.fna.smafadb",
+                "-q",
+                query.to_str().unwrap(),
+                "--query-label",
+                "both",
+            ])
+            .succeeds()
+            .stdout()
+            .is("0	q1	1	0	AGG\n")
+            .unwrap();
+    }
+
+    #[test]
+    fn test_query_slide() {
+        let td = tempfile::TempDir::new().unwrap();
+        let query = td.path().join("query.fna");
+        std::fs::write(&query, ">q1\nAACTTGG\n>q2\nCT\n").unwrap();
+
+        Assert::main_binary()
+            .with_args(&[
+                "query",
+                "-d",
+                "tests/data/random_3_2.fna.smafadb",
+                "-q",
+                query.to_str().unwrap(),
+                "--slide",
+                "--max-num-hits",
+                "99",
+            ])
+            .succeeds()
+            .stdout()
+            .is("0	0	0	CTT	2\n\
+                0	1	1	AGG	4\n")
+            .unwrap();
+
+        Assert::main_binary()
+            .with_args(&[
+                "query",
+                "-d",
+                "tests/data/random_3_2.fna.smafadb",
+                "-q",
+                query.to_str().unwrap(),
+                "--slide",
+                "--max-offset",
+                "3",
+                "--max-num-hits",
+                "99",
+            ])
+            .succeeds()
+            .stdout()
+            .is("0	0	0	CTT	2\n\
+                0	1	2	AGG	0\n")
+            .unwrap();
+
+        Assert::main_binary()
+            .with_args(&[
+                "query",
+                "-d",
+                "tests/data/random_3_2.fna.smafadb",
+                "-q",
+                query.to_str().unwrap(),
+                "--slide",
+                "--min-offset",
+                "3",
+                "--max-num-hits",
+                "99",
+            ])
+            .succeeds()
+            .stdout()
+            .is("0	1	1	AGG	4\n\
+                0	0	2	CTT	3\n")
+            .unwrap();
+
+        Assert::main_binary()
+            .with_args(&[
+                "query",
+                "-d",
+                "tests/data/random_3_2.fna.smafadb",
+                "-q",
+                query.to_str().unwrap(),
+                "--slide",
+                "--min-offset",
+                "3",
+                "--max-offset",
+                "1",
+            ])
+            .fails()
+            .stderr()
+            .contains("The min offset 3 is greater than the max offset 1")
+            .unwrap();
+    }
+
+    #[test]
+    fn test_query_slide_quality() {
+        // Sliding uses the qualities of the bases in each window.
+        let td = tempfile::TempDir::new().unwrap();
+        let query = td.path().join("query.fq");
+        std::fs::write(&query, "@q1\nAGCTG\n+\nIII##\n").unwrap();
+
+        Assert::main_binary()
+            .with_args(&[
+                "query",
+                "-d",
+                "tests/data/random_3_2.fna.smafadb",
+                "-q",
+                query.to_str().unwrap(),
+                "--slide",
+                "--quality-threshold",
+                "20",
+            ])
+            .succeeds()
+            .stdout()
+            .is("0	0	1	CTT	2	0\n")
+            .unwrap();
+    }
+
+    #[test]
+    fn test_makedb_mixed_lengths_and_query() {
+        let tf: tempfile::NamedTempFile = tempfile::NamedTempFile::new().unwrap();
+        let t = tf.path().to_str().unwrap();
+        Assert::main_binary()
+            .with_args(&["makedb", "-i", "tests/data/mixed_lengths.fna", "-d", t])
+            .succeeds()
+            .unwrap();
+
+        // Subjects are numbered by their position in the subject file, and
+        // the query of length 4 matches no window set.
+        let td = tempfile::TempDir::new().unwrap();
+        let query = td.path().join("query.fna");
+        std::fs::write(&query, ">q1\nAGG\n>q2\nAGGTGA\n>q3\nAGGT\n").unwrap();
+        Assert::main_binary()
+            .with_args(&["query", "-d", t, "-q", query.to_str().unwrap()])
+            .succeeds()
+            .stdout()
+            .is("0	2	0	AGG\n\
+                1	3	0	AGGTGA\n")
+            .unwrap()
+    }
+
+    #[test]
+    fn test_cluster_sort_by_abundance() {
+        let td = tempfile::TempDir::new().unwrap();
+        let sizes = td.path().join("sizes.tsv");
+        Assert::main_binary()
+            .with_args(&[
+                "cluster",
+                "-i",
+                "tests/data/cluster_abundance.fna",
+                "-d",
+                "1",
+                "--sort-by-abundance",
+                "--cluster-sizes-output",
+                sizes.to_str().unwrap(),
+            ])
+            .succeeds()
+            .stdout()
+            .is("ATGC\tATGC\n\
+                ATGG\tATGC\n\
+                AAAA\tAAAA\n")
+            .unwrap();
+        assert_eq!(
+            "0\tATGC\t5\n1\tAAAA\t1\n",
+            std::fs::read_to_string(sizes).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_cluster_membership_output() {
+        let td = tempfile::TempDir::new().unwrap();
+        let membership = td.path().join("membership.tsv");
+        Assert::main_binary()
+            .with_args(&[
+                "cluster",
+                "-i",
+                "tests/data/cluster_abundance.fna",
+                "-d",
+                "1",
+                "--sort-by-abundance",
+                "--membership-output",
+                membership.to_str().unwrap(),
+            ])
+            .succeeds()
+            .stdout()
+            .is("ATGC\tATGC\nATGG\tATGC\nAAAA\tAAAA\n")
+            .unwrap();
+        assert_eq!(
+            "seq1\t0\tseq2\t1\tfalse\n\
+             seq2\t0\tseq2\t0\tfalse\n\
+             seq3\t1\tseq3\t0\tfalse\n\
+             seq4\t0\tseq2\t0\ttrue\n",
+            std::fs::read_to_string(membership).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_cluster_centroids_db_and_query() {
+        let td = tempfile::TempDir::new().unwrap();
+        let db = td.path().join("centroids.smafadb");
+        Assert::main_binary()
+            .with_args(&[
+                "cluster",
+                "-i",
+                "tests/data/cluster_abundance.fna",
+                "-d",
+                "1",
+                "--sort-by-abundance",
+                "--centroids-db",
+                db.to_str().unwrap(),
+            ])
+            .succeeds()
+            .unwrap();
+
+        Assert::main_binary()
+            .with_args(&[
+                "query",
+                "-d",
+                db.to_str().unwrap(),
+                "-q",
+                "tests/data/cluster_dummy1.fna",
+            ])
+            .succeeds()
+            .stdout()
+            .is("0\t0\t0\tATGC\n\
+                1\t0\t1\tATGC\n\
+                2\t1\t0\tAAAA\n")
+            .unwrap();
+    }
+
+    #[test]
+    fn test_cluster_reference_db_keeps_cluster_numbers() {
+        let td = tempfile::TempDir::new().unwrap();
+        let db = td.path().join("centroids.smafadb");
+        let membership = td.path().join("membership.tsv");
+        Assert::main_binary()
+            .with_args(&[
+                "cluster",
+                "-i",
+                "tests/data/cluster_dummy1.fna",
+                "-d",
+                "1",
+                "--centroids-db",
+                db.to_str().unwrap(),
+            ])
+            .succeeds()
+            .unwrap();
+
+        Assert::main_binary()
+            .with_args(&[
+                "cluster",
+                "-i",
+                "tests/data/cluster_abundance.fna",
+                "-d",
+                "0",
+                "--reference-db",
+                db.to_str().unwrap(),
+                "--membership-output",
+                membership.to_str().unwrap(),
+            ])
+            .succeeds()
+            .stdout()
+            .is("ATGG\tATGG\nATGC\tATGC\nAAAA\tAAAA\n")
+            .unwrap();
+        assert_eq!(
+            "seq1\t2\tseq1\t0\tfalse\n\
+             seq2\t0\treference_0\t0\tfalse\n\
+             seq3\t1\treference_1\t0\tfalse\n\
+             seq4\t0\treference_0\t0\ttrue\n",
+            std::fs::read_to_string(membership).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_cluster_reassign() {
+        let td = tempfile::TempDir::new().unwrap();
+        let membership = td.path().join("membership.tsv");
+        Assert::main_binary()
+            .with_args(&[
+                "cluster",
+                "-i",
+                "tests/data/cluster_best_hit_changes.fna",
+                "-d",
+                "2",
+                "--reassign",
+                "--membership-output",
+                membership.to_str().unwrap(),
+            ])
+            .succeeds()
+            .stdout()
+            .is("ATGCAAAAA\tATGCAAAAA\n\
+                ATAAAAAAA\tTTAAAAAAA\n\
+                TTAAAAAAA\tTTAAAAAAA\n")
+            .unwrap();
+        // The last column says whether each sequence moved cluster.
+        assert_eq!(
+            "seq1\t0\tseq1\t0\tfalse\tfalse\n\
+             seq2\t1\tseq3\t1\tfalse\ttrue\n\
+             seq3\t1\tseq3\t0\tfalse\tfalse\n\
+             seq4\t1\tseq3\t1\ttrue\ttrue\n",
+            std::fs::read_to_string(membership).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_cluster_multiple_max_divergences() {
+        let td = tempfile::TempDir::new().unwrap();
+        let hierarchy = td.path().join("hierarchy.tsv");
+        Assert::main_binary()
+            .with_args(&[
+                "cluster",
+                "-i",
+                "tests/data/cluster_hierarchy.fna",
+                "-d",
+                "1,6",
+                "--hierarchy-output",
+                hierarchy.to_str().unwrap(),
+            ])
+            .succeeds()
+            .stdout()
+            .is("AAAAAA\tAAAAAA\n\
+                AAAAAC\tAAAAAA\n\
+                AAAACC\tAAAACC\n\
+                CCCCCC\tCCCCCC\n\
+                CCCCCA\tCCCCCC\n")
+            .unwrap();
+        assert_eq!(
+            "read\tmax_divergence_1\tmax_divergence_6\n\
+             s1\t0\t0\n\
+             s2\t0\t0\n\
+             s3\t1\t0\n\
+             s4\t2\t0\n\
+             s5\t2\t0\n",
+            std::fs::read_to_string(&hierarchy).unwrap()
+        );
+
+        Assert::main_binary()
+            .with_args(&[
+                "cluster",
+                "-i",
+                "tests/data/cluster_hierarchy.fna",
+                "-d",
+                "6,1",
+                "--hierarchy-output",
+                hierarchy.to_str().unwrap(),
+            ])
+            .fails()
+            .stderr()
+            .contains("Max divergences must be given in increasing order")
+            .unwrap();
+    }
+
+    #[test]
+    fn test_denoise_min_size() {
+        Assert::main_binary()
+            .with_args(&["denoise", "-i", "tests/data/denoise.fna", "--min-size", "5"])
+            .succeeds()
+            .stdout()
+            .is(">v1;size=23\nATGCATGC\n>v2;size=10\nTTTTATGC\n>e2;size=5\nTTTTATGA\n")
+            .unwrap();
+    }
+
+    #[test]
+    fn test_chimera_reference_db() {
+        let tf: tempfile::NamedTempFile = tempfile::NamedTempFile::new().unwrap();
+        let t = tf.path().to_str().unwrap();
+        Assert::main_binary()
+            .with_args(&["makedb", "-i", "tests/data/chimera_parents.fna", "-d", t])
+            .succeeds()
+            .unwrap();
+
+        Assert::main_binary()
+            .with_args(&[
+                "chimera",
+                "-i",
+                "tests/data/chimera.fna",
+                "--reference-db",
+                t,
+            ])
+            .succeeds()
+            .stdout()
+            .is(
+                "p1\tfalse\treference_0\t0\treference_0\treference_0\t1\t0\n\
+                p2\tfalse\treference_1\t0\treference_1\treference_1\t1\t0\n\
+                c1\ttrue\treference_0\t4\treference_0\treference_1\t4\t0\n\
+                c2\tfalse\treference_0\t4\treference_0\treference_1\t4\t2\n",
+            )
+            .unwrap();
+    }
+
+    #[test]
+    fn test_cluster_min_cluster_size_removes_all() {
+        let td = tempfile::TempDir::new().unwrap();
+        let db = td.path().join("centroids.db");
+        Assert::main_binary()
+            .with_args(&[
+                "cluster",
+                "-i",
+                "tests/data/cluster_abundance.fna",
+                "-d",
+                "0",
+                "--min-cluster-size",
+                "5",
+                "--centroids-db",
+                db.to_str().unwrap(),
+            ])
+            .succeeds()
+            .stdout()
+            .is("")
+            .unwrap();
+        // The database has no windows, so there is nothing to hit.
+        Assert::main_binary()
+            .with_args(&[
+                "query",
+                "-d",
+                db.to_str().unwrap(),
+                "-q",
+                "tests/data/cluster_abundance.fna",
+            ])
+            .succeeds()
+            .stdout()
+            .is("")
+            .unwrap();
+    }
+
+    #[test]
+    fn test_cluster_k_medoids() {
+        let td = tempfile::TempDir::new().unwrap();
+        let membership = td.path().join("membership.tsv");
+        Assert::main_binary()
+            .with_args(&[
+                "cluster",
+                "--input",
+                "tests/data/cluster_medoids.fna",
+                "--k-medoids",
+                "2",
+                "--seed",
+                "1",
+                "--membership-output",
+                membership.to_str().unwrap(),
+            ])
+            .succeeds()
+            .stdout()
+            .is("AAAAAA\tAAAAAC\n\
+                 AAAAAC\tAAAAAC\n\
+                 AAAACC\tAAAAAC\n\
+                 CCCCCC\tCCCCCA\n\
+                 CCCCCA\tCCCCCA\n\
+                 CCCCAA\tCCCCCA\n")
+            .unwrap();
+        assert_eq!(
+            "s1\t1\ts2\t1\tfalse\n\
+             s2\t1\ts2\t0\tfalse\n\
+             s3\t1\ts2\t1\tfalse\n\
+             s4\t0\ts5\t1\tfalse\n\
+             s5\t0\ts5\t0\tfalse\n\
+             s6\t0\ts5\t1\tfalse\n",
+            std::fs::read_to_string(membership).unwrap()
+        );
+
+        // The max divergence is not used to partition around medoids.
+        Assert::main_binary()
+            .with_args(&[
+                "cluster",
+                "--input",
+                "tests/data/cluster_medoids.fna",
+                "--k-medoids",
+                "2",
+                "-d",
+                "3",
+            ])
+            .fails()
+            .stderr()
+            .contains("cannot be used with")
+            .unwrap();
+    }
+
+    fn make_multi_marker_db(td: &tempfile::TempDir) -> String {
+        let manifest = td.path().join("manifest.tsv");
+        std::fs::write(
+            &manifest,
+            "short\ttests/data/random_3_2.fna\nlong\ttests/data/degenerate.fna\n",
+        )
+        .unwrap();
+        let db = td.path().join("multi.smafadb");
+        Assert::main_binary()
+            .with_args(&[
+                "makedb",
+                "--manifest",
+                manifest.to_str().unwrap(),
+                "-d",
+                db.to_str().unwrap(),
+            ])
+            .succeeds()
+            .unwrap();
+        db.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn test_multi_marker_query_by_length() {
+        let td = tempfile::TempDir::new().unwrap();
+        let db = make_multi_marker_db(&td);
+
+        Assert::main_binary()
+            .with_args(&["query", "-d", &db, "-q", "tests/data/degenerate.fna"])
+            .succeeds()
+            .stdout()
+            .is("0	0	0	CTTNGG	long\n\
+                1	1	0	AGGTGA	long\n\
+                2	2	0	NACTTT	long\n")
+            .unwrap()
+    }
+
+    #[test]
+    fn test_multi_marker_query_by_header_field() {
+        let td = tempfile::TempDir::new().unwrap();
+        let db = make_multi_marker_db(&td);
+        let query = td.path().join("query.fna");
+        std::fs::write(
+            &query,
+            ">q1 short\nAGG\n>q2 long\nAGGTGA\n>q3 long\nAGG\n>q4\nAGG\n",
+        )
+        .unwrap();
+
+        Assert::main_binary()
+            .with_args(&[
+                "query",
+                "-d",
+                &db,
+                "-q",
+                query.to_str().unwrap(),
+                "--marker-header-field",
+                "2",
+            ])
+            .succeeds()
+            .stdout()
+            .is("0	1	0	AGG	short\n\
+                1	1	0	AGGTGA	long\n")
+            .unwrap()
+    }
+
     // #[test]
     // fn test_db_version_incompatibility(){
     //     Assert::main_binary()